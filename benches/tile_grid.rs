@@ -0,0 +1,110 @@
+//! Compares `TileGrid` with the `HashMap`/`HashSet` storage it replaced on a 512x512 level.
+//! Run with `cargo bench --bench tile_grid`.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use engine::types::Vec2;
+use rs_game::tile_grid::TileGrid;
+
+const SIZE: i32 = 512;
+const ITERATIONS: u32 = 5;
+
+fn time<F: FnMut()>(name: &str, mut f: F) -> Duration {
+    f();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed = start.elapsed() / ITERATIONS;
+    println!("{:<32} {:>10.3}ms", name, elapsed.as_secs_f64() * 1000.0);
+    elapsed
+}
+
+/// A wall on every 7th column, with a gap every 11th row, so the wave has to wind around.
+fn is_wall(pos: Vec2) -> bool {
+    pos.x % 7 == 6 && pos.y % 11 != 0
+}
+
+fn tiles() -> impl Iterator<Item = Vec2> {
+    (0..SIZE).flat_map(|y| (0..SIZE).map(move |x| Vec2::new(x, y)))
+}
+
+fn neighbors(pos: Vec2) -> [Vec2; 4] {
+    [
+        Vec2::new(pos.x + 1, pos.y),
+        Vec2::new(pos.x - 1, pos.y),
+        Vec2::new(pos.x, pos.y + 1),
+        Vec2::new(pos.x, pos.y - 1),
+    ]
+}
+
+fn main() {
+    let map_tiles: HashMap<Vec2, Vec2> = tiles().map(|pos| (pos, Vec2::new(32, 0))).collect();
+    let map_collision: HashSet<Vec2> = tiles().filter(|pos| is_wall(*pos)).collect();
+    let grid_tiles: TileGrid<Vec2> = tiles().map(|pos| (pos, Vec2::new(32, 0))).collect();
+    let grid_collision: TileGrid<()> = tiles()
+        .filter(|pos| is_wall(*pos))
+        .map(|pos| (pos, ()))
+        .collect();
+
+    println!("lookup every tile");
+    time("  HashMap", || {
+        for pos in tiles() {
+            black_box(map_tiles.get(&pos));
+        }
+    });
+    time("  TileGrid", || {
+        for pos in tiles() {
+            black_box(grid_tiles.get(pos));
+        }
+    });
+
+    println!("collision checks");
+    time("  HashSet", || {
+        for pos in tiles() {
+            black_box(map_collision.contains(&pos));
+        }
+    });
+    time("  TileGrid", || {
+        for pos in tiles() {
+            black_box(grid_collision.contains(pos));
+        }
+    });
+
+    println!("flow field wave from the center");
+    let start = Vec2::new(SIZE / 2, SIZE / 2);
+    time("  HashMap", || {
+        let mut field: HashMap<Vec2, i32> = tiles()
+            .map(|pos| (pos, if is_wall(pos) { -1 } else { 0 }))
+            .collect();
+        let mut queue = VecDeque::from([(start, 1)]);
+        field.insert(start, 1);
+        while let Some((pos, distance)) = queue.pop_front() {
+            for next in neighbors(pos) {
+                if field.get(&next) == Some(&0) {
+                    field.insert(next, distance + 1);
+                    queue.push_back((next, distance + 1));
+                }
+            }
+        }
+        black_box(field);
+    });
+    time("  TileGrid", || {
+        let mut field: TileGrid<i32> = tiles()
+            .map(|pos| (pos, if is_wall(pos) { -1 } else { 0 }))
+            .collect();
+        let mut queue = VecDeque::from([(start, 1)]);
+        field.insert(start, 1);
+        while let Some((pos, distance)) = queue.pop_front() {
+            for next in neighbors(pos) {
+                if field.get(next) == Some(&0) {
+                    field.insert(next, distance + 1);
+                    queue.push_back((next, distance + 1));
+                }
+            }
+        }
+        black_box(field);
+    });
+}
@@ -0,0 +1,529 @@
+//! Data driven NPC behavior. A `StateMachine` moves an NPC between a handful of `AiState`s by the
+//! transitions of a `Behavior`, listed by name in the manifest. Each state steers the NPC through
+//! its `Path` and `Velocity` like the other enemies, so new kinds of NPC don't need new systems.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bevy_ecs::prelude::*;
+use engine::types::Vec2F;
+use serde::Deserialize;
+
+use crate::components::*;
+use crate::pathfinding::Heuristic;
+use crate::raycast::line_of_sight;
+use crate::resources::Level;
+use crate::systems::steer_along_path;
+
+/// How close, in tiles, an NPC has to be to where it spawned to be `AtHome`.
+const HOME_RADIUS: f32 = 0.5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum AiState {
+    /// Slows to a stop.
+    Idle,
+    /// Follows its `Path`.
+    Patrol,
+    /// Paths to the player, repathing each time the `Path` timer runs out.
+    Chase,
+    /// Runs straight away from the player.
+    Flee,
+    /// Paths back to where it spawned, or heads straight there if there is no path.
+    ReturnHome,
+    /// Stands its ground next to the player.
+    Attack,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+pub enum Condition {
+    /// The player is in sight within the NPC's `AggroDistance`.
+    Aggro,
+    /// The player is further away than the NPC's `LeashDistance`.
+    Leashed,
+    /// The player is in sight within this many tiles.
+    SeesPlayer(f32),
+    /// The player is out of sight, or further away than this many tiles.
+    LostPlayer(f32),
+    PlayerWithin(f32),
+    PlayerBeyond(f32),
+    /// The NPC has been in its current state for this many seconds.
+    After(f32),
+    /// The NPC is back where it spawned.
+    AtHome,
+    /// The NPC has reached the end of its `Path`.
+    PathDone,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct Transition {
+    /// The states the transition can be taken from, any state when empty.
+    #[serde(default)]
+    pub from: Vec<AiState>,
+    pub to: AiState,
+    pub when: Condition,
+}
+
+/// Transitions are checked in order each frame, and the first one that applies is taken.
+#[derive(Clone, Deserialize)]
+pub struct Behavior {
+    pub initial: AiState,
+    pub transitions: Vec<Transition>,
+}
+
+impl Default for Behavior {
+    fn default() -> Self {
+        Self {
+            initial: AiState::Idle,
+            transitions: Vec::new(),
+        }
+    }
+}
+
+/// The behaviors listed in the manifest, by name.
+#[derive(Default)]
+pub struct Behaviors(HashMap<String, Arc<Behavior>>);
+
+impl Behaviors {
+    pub fn new(behaviors: HashMap<String, Behavior>) -> Self {
+        Self(
+            behaviors
+                .into_iter()
+                .map(|(name, behavior)| (name, Arc::new(behavior)))
+                .collect(),
+        )
+    }
+    pub fn get(&self, name: &str) -> Option<Arc<Behavior>> {
+        self.0.get(name).cloned()
+    }
+}
+
+#[derive(Component)]
+pub struct StateMachine {
+    pub behavior: Arc<Behavior>,
+    pub state: AiState,
+    /// Time spent in the current state.
+    pub elapsed: Duration,
+    /// Where the NPC spawned.
+    pub home: Vec2F,
+}
+
+impl StateMachine {
+    pub fn new(behavior: Arc<Behavior>, home: Vec2F) -> Self {
+        Self {
+            state: behavior.initial,
+            behavior,
+            elapsed: Duration::ZERO,
+            home,
+        }
+    }
+}
+
+/// What an NPC knows about its surroundings when checking conditions.
+struct Senses<'a> {
+    level: &'a Level,
+    pos: Vec2F,
+    player_pos: Vec2F,
+    player_distance: f32,
+    aggro_distance: f32,
+    leash_distance: f32,
+    path_done: bool,
+}
+
+impl Condition {
+    fn holds(self, machine: &StateMachine, senses: &Senses) -> bool {
+        let sees_player = |range| line_of_sight(senses.level, senses.pos, senses.player_pos, range);
+        match self {
+            Self::Aggro => sees_player(senses.aggro_distance),
+            Self::Leashed => senses.player_distance > senses.leash_distance,
+            Self::SeesPlayer(range) => sees_player(range),
+            Self::LostPlayer(range) => !sees_player(range),
+            Self::PlayerWithin(range) => senses.player_distance <= range,
+            Self::PlayerBeyond(range) => senses.player_distance > range,
+            Self::After(seconds) => machine.elapsed.as_secs_f32() >= seconds,
+            Self::AtHome => distance(senses.pos, machine.home) <= HOME_RADIUS,
+            Self::PathDone => senses.path_done,
+        }
+    }
+}
+
+fn distance(a: Vec2F, b: Vec2F) -> f32 {
+    f32::hypot(b.x - a.x, b.y - a.y)
+}
+
+/// Takes the first transition of each `StateMachine` that applies, then moves the NPC as its
+/// state dictates.
+pub fn update_state_machines(
+    mut commands: Commands,
+    mut npc_query: Query<
+        (
+            Entity,
+            &mut StateMachine,
+            &Position,
+            &mut Velocity,
+            &Speed,
+            &mut Path,
+            &AggroDistance,
+            &LeashDistance,
+            Option<&PathRequest>,
+        ),
+        Without<Player>,
+    >,
+    player_query: Query<(&Player, &Position)>,
+    elapsed_time: Res<Duration>,
+    level: Res<Level>,
+) {
+    let (_, player_pos) = player_query.single();
+    for (
+        entity,
+        mut machine,
+        pos,
+        mut vel,
+        spd,
+        mut path,
+        aggro_distance,
+        leash_distance,
+        request,
+    ) in npc_query.iter_mut()
+    {
+        machine.elapsed += *elapsed_time;
+        let senses = Senses {
+            level: &level,
+            pos: pos.0,
+            player_pos: player_pos.0,
+            player_distance: distance(pos.0, player_pos.0),
+            aggro_distance: aggro_distance.0,
+            leash_distance: leash_distance.0,
+            path_done: path.points.is_empty()
+                && path
+                    .next_point
+                    .map_or(true, |point| distance(pos.0, point) < 0.01),
+        };
+        let transition = machine
+            .behavior
+            .transitions
+            .iter()
+            .find(|transition| {
+                transition.to != machine.state
+                    && (transition.from.is_empty() || transition.from.contains(&machine.state))
+                    && transition.when.holds(&machine, &senses)
+            })
+            .map(|transition| transition.to);
+        let entered = transition.is_some();
+        if let Some(state) = transition {
+            machine.state = state;
+            machine.elapsed = Duration::ZERO;
+        }
+        match machine.state {
+            AiState::Idle => vel.0 *= 0.97 * elapsed_time.as_secs_f32(),
+            AiState::Attack => vel.0 = Vec2F::new(0.0, 0.0),
+            AiState::Patrol => steer_along_path(pos.0, &mut path, &mut vel, spd.0, *elapsed_time),
+            AiState::Chase => {
+                if entered || path.timer.done {
+                    commands.entity(entity).insert(PathRequest {
+                        target: player_pos.0,
+                        heuristic: Heuristic::Octile,
+                    });
+                    path.timer.restart();
+                }
+                steer_along_path(pos.0, &mut path, &mut vel, spd.0, *elapsed_time);
+            }
+            AiState::Flee => {
+                if senses.player_distance > 0.1 {
+                    vel.0.x = ((pos.0.x - player_pos.0.x) / senses.player_distance) * spd.0;
+                    vel.0.y = ((pos.0.y - player_pos.0.y) / senses.player_distance) * spd.0;
+                    vel.0 *= elapsed_time.as_secs_f32();
+                }
+            }
+            AiState::ReturnHome => {
+                if entered {
+                    commands.entity(entity).insert(PathRequest {
+                        target: machine.home,
+                        heuristic: Heuristic::Octile,
+                    });
+                } else if request.is_none() && path.next_point.is_none() {
+                    // There is no path home, so the NPC heads straight for it and lets the walls
+                    // stop it.
+                    let home_distance = distance(pos.0, machine.home);
+                    if home_distance > HOME_RADIUS {
+                        vel.0.x = ((machine.home.x - pos.0.x) / home_distance) * spd.0;
+                        vel.0.y = ((machine.home.y - pos.0.y) / home_distance) * spd.0;
+                        vel.0 *= elapsed_time.as_secs_f32();
+                    } else {
+                        vel.0 = Vec2F::new(0.0, 0.0);
+                    }
+                }
+                steer_along_path(pos.0, &mut path, &mut vel, spd.0, *elapsed_time);
+            }
+        }
+    }
+}
+
+/// How close, in tiles, an enemy has to get to a patrol waypoint to move on to the next.
+const WAYPOINT_RADIUS: f32 = 0.1;
+
+/// Requests a path to the next waypoint of each `Patrol` while its enemy is patrolling: when a
+/// `StateMachine` is in `Patrol`, or otherwise when the enemy isn't `Aggroed`. Leaving the route
+/// drops the path, and coming back requests a new one to the waypoint it was heading to. A
+/// waypoint that can't be reached is skipped.
+pub fn update_patrols(
+    mut commands: Commands,
+    mut patrol_query: Query<(
+        Entity,
+        &mut Patrol,
+        &Position,
+        &mut Path,
+        Option<&PathRequest>,
+        Option<&Aggroed>,
+        Option<&StateMachine>,
+    )>,
+) {
+    for (entity, mut patrol, pos, mut path, request, aggroed, machine) in patrol_query.iter_mut() {
+        let patrolling = match machine {
+            Some(machine) => machine.state == AiState::Patrol,
+            None => !aggroed.map_or(false, |aggroed| aggroed.0),
+        };
+        if !patrolling {
+            if patrol.on_route || patrol.requested {
+                if patrol.requested {
+                    commands.entity(entity).remove::<PathRequest>();
+                }
+                patrol.on_route = false;
+                patrol.requested = false;
+                path.points.clear();
+                path.next_point = None;
+            }
+            continue;
+        }
+        if patrol.requested {
+            if request.is_some() {
+                continue;
+            }
+            // `handle_path_requests` leaves the path empty when the waypoint can't be reached.
+            patrol.requested = false;
+            if path.next_point.is_some() {
+                patrol.on_route = true;
+            } else {
+                patrol.advance();
+            }
+        }
+        let waypoint = match patrol.next_waypoint() {
+            Some(waypoint) => waypoint,
+            None => continue,
+        };
+        if patrol.on_route && distance(pos.0, waypoint) < WAYPOINT_RADIUS {
+            let reached = patrol.next;
+            patrol.advance();
+            // A route with a single waypoint has nowhere further to go.
+            patrol.on_route = patrol.next == reached;
+        }
+        if !patrol.on_route {
+            if let Some(target) = patrol.next_waypoint() {
+                commands.entity(entity).insert(PathRequest {
+                    target,
+                    heuristic: Heuristic::Octile,
+                });
+                patrol.requested = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use engine::types::Vec2;
+
+    use super::*;
+
+    fn run_patrols(world: &mut World) {
+        let mut stage = SystemStage::single_threaded().with_system(update_patrols);
+        stage.run(world);
+    }
+
+    fn patrolling_enemy(world: &mut World) -> Entity {
+        let waypoints = vec![Vec2F::new(3.0, 0.0), Vec2F::new(6.0, 0.0)];
+        world
+            .spawn()
+            .insert(Patrol::new(waypoints, PatrolMode::Loop))
+            .insert(Position::new(0.0, 0.0))
+            .insert(Path::default())
+            .insert(Aggroed(false))
+            .id()
+    }
+
+    fn requested_target(world: &World, entity: Entity) -> Option<(f32, f32)> {
+        world
+            .get::<PathRequest>(entity)
+            .map(|request| (request.target.x, request.target.y))
+    }
+
+    #[test]
+    fn goes_on_route_once_a_path_arrives() {
+        let mut world = World::new();
+        let enemy = patrolling_enemy(&mut world);
+        run_patrols(&mut world);
+        assert_eq!(requested_target(&world, enemy), Some((3.0, 0.0)));
+        assert!(!world.get::<Patrol>(enemy).unwrap().on_route);
+        // As `handle_path_requests` would answer it.
+        let mut entity = world.entity_mut(enemy);
+        entity.remove::<PathRequest>();
+        let mut path = entity.get_mut::<Path>().unwrap();
+        path.points = vec![Vec2F::new(0.0, 0.0), Vec2F::new(3.0, 0.0)];
+        path.next_point = Some(Vec2F::new(0.0, 0.0));
+        run_patrols(&mut world);
+        let patrol = world.get::<Patrol>(enemy).unwrap();
+        assert!(patrol.on_route && !patrol.requested);
+        assert_eq!(patrol.next, Some(0));
+        assert!(requested_target(&world, enemy).is_none());
+    }
+
+    #[test]
+    fn skips_a_waypoint_that_cant_be_reached() {
+        let mut world = World::new();
+        let enemy = patrolling_enemy(&mut world);
+        run_patrols(&mut world);
+        // An unreachable waypoint is answered with an empty path.
+        world.entity_mut(enemy).remove::<PathRequest>();
+        run_patrols(&mut world);
+        let patrol = world.get::<Patrol>(enemy).unwrap();
+        assert!(!patrol.on_route && patrol.requested);
+        assert_eq!(patrol.next, Some(1));
+        assert_eq!(requested_target(&world, enemy), Some((6.0, 0.0)));
+    }
+
+    #[test]
+    fn drops_the_route_when_aggroed() {
+        let mut world = World::new();
+        let enemy = patrolling_enemy(&mut world);
+        run_patrols(&mut world);
+        world.get_mut::<Aggroed>(enemy).unwrap().0 = true;
+        run_patrols(&mut world);
+        let patrol = world.get::<Patrol>(enemy).unwrap();
+        assert!(!patrol.on_route && !patrol.requested);
+        assert!(requested_target(&world, enemy).is_none());
+        assert!(world.get::<Path>(enemy).unwrap().next_point.is_none());
+    }
+
+    #[test]
+    fn returns_home_directly_without_a_path() {
+        let mut world = World::new();
+        let background = (0..10)
+            .map(|x| (Vec2::new(x, 0), Vec2::new(0, 0)))
+            .collect();
+        world.insert_resource(Level::new("test").background_tiles(background).build());
+        world.insert_resource(Duration::from_millis(100));
+        world.spawn().insert(Player).insert(Position::new(9.0, 0.0));
+        let behavior = Arc::new(Behavior {
+            initial: AiState::ReturnHome,
+            transitions: Vec::new(),
+        });
+        let npc = world
+            .spawn()
+            .insert(StateMachine::new(behavior, Vec2F::new(0.0, 0.0)))
+            .insert(Position::new(5.0, 0.0))
+            .insert(Velocity::default())
+            .insert(Speed::new(2.0))
+            .insert(Path::default())
+            .insert(AggroDistance::new(1.0))
+            .insert(LeashDistance::new(1.0))
+            .id();
+        let mut stage = SystemStage::single_threaded().with_system(update_state_machines);
+        stage.run(&mut world);
+        let vel = world.get::<Velocity>(npc).unwrap().0;
+        assert!((vel.x - -0.2).abs() < 1e-6, "{}", vel.x);
+        assert_eq!(vel.y, 0.0);
+    }
+
+    /// An NPC following `transitions` from `initial` at x = 2 on an open row, with the player at
+    /// `player_x`. It aggroes within 5 tiles and gives up past 8.
+    fn npc_world(initial: AiState, transitions: Vec<Transition>, player_x: f32) -> (World, Entity) {
+        let mut world = World::new();
+        let background = (0..20)
+            .map(|x| (Vec2::new(x, 0), Vec2::new(0, 0)))
+            .collect();
+        world.insert_resource(Level::new("test").background_tiles(background).build());
+        world.insert_resource(Duration::from_millis(100));
+        world
+            .spawn()
+            .insert(Player)
+            .insert(Position::new(player_x, 0.0));
+        let behavior = Arc::new(Behavior {
+            initial,
+            transitions,
+        });
+        let npc = world
+            .spawn()
+            .insert(StateMachine::new(behavior, Vec2F::new(2.0, 0.0)))
+            .insert(Position::new(2.0, 0.0))
+            .insert(Velocity::default())
+            .insert(Speed::new(2.0))
+            .insert(Path::default())
+            .insert(AggroDistance::new(5.0))
+            .insert(LeashDistance::new(8.0))
+            .id();
+        (world, npc)
+    }
+
+    fn run_state_machines(world: &mut World) {
+        let mut stage = SystemStage::single_threaded().with_system(update_state_machines);
+        stage.run(world);
+    }
+
+    fn state(world: &World, npc: Entity) -> AiState {
+        world.get::<StateMachine>(npc).unwrap().state
+    }
+
+    #[test]
+    fn chases_once_the_player_is_within_aggro_distance() {
+        let chase = || {
+            vec![Transition {
+                from: vec![AiState::Idle],
+                to: AiState::Chase,
+                when: Condition::Aggro,
+            }]
+        };
+        let (mut world, npc) = npc_world(AiState::Idle, chase(), 10.0);
+        run_state_machines(&mut world);
+        assert_eq!(state(&world, npc), AiState::Idle);
+        assert!(world.get::<PathRequest>(npc).is_none());
+        let (mut world, npc) = npc_world(AiState::Idle, chase(), 6.0);
+        run_state_machines(&mut world);
+        assert_eq!(state(&world, npc), AiState::Chase);
+        assert_eq!(requested_target(&world, npc), Some((6.0, 0.0)));
+    }
+
+    #[test]
+    fn returns_home_once_leashed() {
+        let leash = || {
+            vec![Transition {
+                from: vec![AiState::Chase],
+                to: AiState::ReturnHome,
+                when: Condition::Leashed,
+            }]
+        };
+        let (mut world, npc) = npc_world(AiState::Chase, leash(), 9.0);
+        run_state_machines(&mut world);
+        assert_eq!(state(&world, npc), AiState::Chase);
+        let (mut world, npc) = npc_world(AiState::Chase, leash(), 11.0);
+        run_state_machines(&mut world);
+        assert_eq!(state(&world, npc), AiState::ReturnHome);
+        assert_eq!(requested_target(&world, npc), Some((2.0, 0.0)));
+    }
+
+    #[test]
+    fn times_out_of_a_state() {
+        let transitions = vec![Transition {
+            from: Vec::new(),
+            to: AiState::Patrol,
+            when: Condition::After(0.25),
+        }];
+        let (mut world, npc) = npc_world(AiState::Idle, transitions, 10.0);
+        run_state_machines(&mut world);
+        run_state_machines(&mut world);
+        assert_eq!(state(&world, npc), AiState::Idle);
+        run_state_machines(&mut world);
+        assert_eq!(state(&world, npc), AiState::Patrol);
+        let machine = world.get::<StateMachine>(npc).unwrap();
+        assert_eq!(machine.elapsed, Duration::ZERO);
+    }
+}
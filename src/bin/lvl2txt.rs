@@ -0,0 +1,34 @@
+use std::env;
+use std::path::Path;
+use std::process::exit;
+
+use rs_game::file::read_level;
+use rs_game::level_text::save_level_text;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 || args.len() > 4 {
+        eprintln!("usage: {} <in.lvl> <out.ron> [level name]", args[0]);
+        exit(2);
+    }
+    let input = Path::new(&args[1]);
+    let output = Path::new(&args[2]);
+    let name = match args.get(3) {
+        Some(name) => name.clone(),
+        None => input
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    };
+    let level = match read_level(input, &name) {
+        Ok(builder) => builder.build(),
+        Err(why) => {
+            eprintln!("Couldn't load {}: {}", input.display(), why);
+            exit(1);
+        }
+    };
+    if let Err(why) = save_level_text(&level, output) {
+        eprintln!("Couldn't save {}: {}", output.display(), why);
+        exit(1);
+    }
+}
@@ -0,0 +1,27 @@
+use std::env;
+use std::path::Path;
+use std::process::exit;
+
+use rs_game::file::save_level;
+use rs_game::level_text::read_level_text;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <in.ron> <out.lvl>", args[0]);
+        exit(2);
+    }
+    let input = Path::new(&args[1]);
+    let output = Path::new(&args[2]);
+    let level = match read_level_text(input) {
+        Ok(builder) => builder.build(),
+        Err(why) => {
+            eprintln!("Couldn't load {}: {}", input.display(), why);
+            exit(1);
+        }
+    };
+    if let Err(why) = save_level(&level, output) {
+        eprintln!("Couldn't save {}: {}", output.display(), why);
+        exit(1);
+    }
+}
@@ -0,0 +1,437 @@
+//! Movement of bodies against the collision tiles of a level, and overlaps between bodies.
+
+use std::collections::{HashMap, HashSet};
+
+use bevy_ecs::prelude::*;
+use engine::types::{Vec2, Vec2F};
+
+use crate::components::{Collider, Position, Sensor, Shape};
+use crate::resources::Level;
+
+/// How far inside its shape a body is checked against walls, so that a body resting flush
+/// against a wall isn't taken to be in it.
+pub const SKIN: f32 = 0.001;
+
+pub struct Sweep {
+    pub position: Vec2F,
+    /// Whether the body was stopped moving horizontally.
+    pub blocked_x: bool,
+    /// Whether the body was stopped moving vertically.
+    pub blocked_y: bool,
+}
+
+/// Moves a body by `delta`, stopping the bounding box of its `Collider` against the first
+/// collision tile in its way. The axes are swept one after the other, x first, over every tile
+/// the leading edge of the box crosses, so a fast body can't skip a wall and a body sliding
+/// along a wall doesn't catch on the corners between its tiles.
+pub fn sweep_body(level: &Level, collider: &Collider, pos: Vec2F, delta: Vec2F) -> Sweep {
+    let half_extents = collider.half_extents();
+    let center = collider.center(pos);
+    let (x, blocked_x) = sweep_axis(
+        level,
+        center.x,
+        delta.x,
+        half_extents.x,
+        (center.y - half_extents.y, center.y + half_extents.y),
+        |column, row| Vec2::new(column, row),
+    );
+    let (y, blocked_y) = sweep_axis(
+        level,
+        center.y,
+        delta.y,
+        half_extents.y,
+        (x - half_extents.x, x + half_extents.x),
+        |row, column| Vec2::new(column, row),
+    );
+    Sweep {
+        position: Vec2F::new(x - collider.offset.x, y - collider.offset.y),
+        blocked_x,
+        blocked_y,
+    }
+}
+
+/// Moves the center of a box by `delta` along one axis, with `half` its half size along the
+/// axis and `across` the extent of its sides across it. `tile` builds a tile position from a line
+/// along the axis and one across it. Returns where the center ends up, and whether the box hit a
+/// wall.
+fn sweep_axis(
+    level: &Level,
+    center: f32,
+    delta: f32,
+    half: f32,
+    across: (f32, f32),
+    tile: impl Fn(i32, i32) -> Vec2,
+) -> (f32, bool) {
+    let first_row = (across.0 + SKIN).floor() as i32;
+    let last_row = (across.1 - SKIN).ceil() as i32 - 1;
+    let blocked =
+        |line: i32| (first_row..=last_row).any(|row| level.collision.contains(tile(line, row)));
+    if delta > 0.0 {
+        // The last line the leading edge is in, before and after moving.
+        let from = (center + half - SKIN).ceil() as i32 - 1;
+        let to = (center + half + delta - SKIN).ceil() as i32 - 1;
+        if let Some(line) = (from + 1..=to).find(|line| blocked(*line)) {
+            return ((line as f32 - half).max(center), true);
+        }
+    } else if delta < 0.0 {
+        let from = (center - half + SKIN).floor() as i32;
+        let to = (center - half + delta + SKIN).floor() as i32;
+        if let Some(line) = (to..from).rev().find(|line| blocked(*line)) {
+            return ((line as f32 + 1.0 + half).min(center), true);
+        }
+    }
+    (center + delta, false)
+}
+
+/// Whether the shapes of two bodies overlap. Bodies that only touch don't.
+pub fn overlaps(a: &Collider, a_pos: Vec2F, b: &Collider, b_pos: Vec2F) -> bool {
+    let (a_center, b_center) = (a.center(a_pos), b.center(b_pos));
+    match (a.shape, b.shape) {
+        (Shape::Circle(a_radius), Shape::Circle(b_radius)) => {
+            distance_squared(a_center, b_center) < (a_radius + b_radius).powi(2)
+        }
+        (Shape::Box(half_extents), Shape::Circle(radius)) => {
+            box_overlaps_circle(a_center, half_extents, b_center, radius)
+        }
+        (Shape::Circle(radius), Shape::Box(half_extents)) => {
+            box_overlaps_circle(b_center, half_extents, a_center, radius)
+        }
+        (Shape::Box(_), Shape::Box(_)) => {
+            let (a_min, a_max) = a.bounds(a_pos);
+            let (b_min, b_max) = b.bounds(b_pos);
+            boxes_overlap((a_min, a_max), (b_min, b_max))
+        }
+    }
+}
+
+fn distance_squared(a: Vec2F, b: Vec2F) -> f32 {
+    (b.x - a.x).powi(2) + (b.y - a.y).powi(2)
+}
+
+fn boxes_overlap(a: (Vec2F, Vec2F), b: (Vec2F, Vec2F)) -> bool {
+    a.0.x < b.1.x && b.0.x < a.1.x && a.0.y < b.1.y && b.0.y < a.1.y
+}
+
+fn box_overlaps_circle(center: Vec2F, half_extents: Vec2F, circle: Vec2F, radius: f32) -> bool {
+    let closest = Vec2F::new(
+        circle
+            .x
+            .clamp(center.x - half_extents.x, center.x + half_extents.x),
+        circle
+            .y
+            .clamp(center.y - half_extents.y, center.y + half_extents.y),
+    );
+    distance_squared(closest, circle) < radius * radius
+}
+
+/// A uniform grid of tile sized cells, each listing the bodies whose bounding box covers it.
+/// Only bodies sharing a cell can overlap, so only they are checked against each other.
+#[derive(Default)]
+pub struct BroadphaseGrid {
+    /// Each body, with the first cell it covers.
+    bodies: Vec<(Entity, Vec2F, Collider, Vec2)>,
+    cells: HashMap<Vec2, Vec<usize>>,
+}
+
+impl BroadphaseGrid {
+    pub fn clear(&mut self) {
+        self.bodies.clear();
+        self.cells.clear();
+    }
+    pub fn insert(&mut self, entity: Entity, pos: Vec2F, collider: Collider) {
+        let index = self.bodies.len();
+        let (first, last) = Self::cells_of(&collider, pos);
+        self.bodies.push((entity, pos, collider, first));
+        for y in first.y..=last.y {
+            for x in first.x..=last.x {
+                self.cells.entry(Vec2::new(x, y)).or_default().push(index);
+            }
+        }
+    }
+    /// The first and last cells the bounding box of a body covers.
+    fn cells_of(collider: &Collider, pos: Vec2F) -> (Vec2, Vec2) {
+        let (min, max) = collider.bounds(pos);
+        (
+            Vec2::new(min.x.floor() as i32, min.y.floor() as i32),
+            Vec2::new(max.x.floor() as i32, max.y.floor() as i32),
+        )
+    }
+    /// Every pair of bodies whose shapes overlap, once each, with the smaller `Entity` first.
+    pub fn overlapping_pairs(&self) -> impl Iterator<Item = (Entity, Entity)> + '_ {
+        self.cells.iter().flat_map(move |(cell, indices)| {
+            indices.iter().enumerate().flat_map(move |(i, a)| {
+                indices[i + 1..].iter().filter_map(move |b| {
+                    let (a_entity, a_pos, a_collider, a_first) = &self.bodies[*a];
+                    let (b_entity, b_pos, b_collider, b_first) = &self.bodies[*b];
+                    // Bodies sharing several cells are only paired in the first of them, the one
+                    // holding the top left corner of where their bounding boxes meet.
+                    let first = Vec2::new(a_first.x.max(b_first.x), a_first.y.max(b_first.y));
+                    if first != *cell || !overlaps(a_collider, *a_pos, b_collider, *b_pos) {
+                        return None;
+                    }
+                    Some((*a_entity.min(b_entity), *a_entity.max(b_entity)))
+                })
+            })
+        })
+    }
+}
+
+/// Sent when the shapes of two bodies start overlapping, with the smaller `Entity` first. Not sent
+/// for a `Sensor`.
+pub struct CollisionStarted(pub Entity, pub Entity);
+
+/// Sent when the shapes of two bodies stop overlapping, including when one of them is despawned,
+/// with the smaller `Entity` first. Not sent for a `Sensor`.
+pub struct CollisionEnded(pub Entity, pub Entity);
+
+/// The pairs of bodies that overlapped as of the last `detect_collisions`, including those with a
+/// `Sensor`.
+#[derive(Default)]
+pub struct Contacts {
+    /// Each pair, and whether either body is a `Sensor`.
+    pairs: HashMap<(Entity, Entity), bool>,
+    previous: HashMap<(Entity, Entity), bool>,
+}
+
+impl Contacts {
+    pub fn contains(&self, a: Entity, b: Entity) -> bool {
+        self.pairs.contains_key(&(a.min(b), a.max(b)))
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (Entity, Entity)> + '_ {
+        self.pairs.keys().copied()
+    }
+}
+
+/// Finds the bodies whose `Collider`s overlap, and sends a `CollisionStarted` or `CollisionEnded`
+/// for each pair without a `Sensor` that began or stopped overlapping since the last frame.
+pub fn detect_collisions(
+    query: Query<(Entity, &Position, &Collider, Option<&Sensor>)>,
+    mut grid: ResMut<BroadphaseGrid>,
+    mut contacts: ResMut<Contacts>,
+    mut started: EventWriter<CollisionStarted>,
+    mut ended: EventWriter<CollisionEnded>,
+) {
+    grid.clear();
+    let mut sensors = HashSet::new();
+    for (entity, pos, collider, sensor) in query.iter() {
+        grid.insert(entity, pos.0, *collider);
+        if sensor.is_some() {
+            sensors.insert(entity);
+        }
+    }
+    let contacts = contacts.as_mut();
+    std::mem::swap(&mut contacts.pairs, &mut contacts.previous);
+    contacts.pairs.clear();
+    contacts.pairs.extend(
+        grid.overlapping_pairs()
+            .map(|(a, b)| ((a, b), sensors.contains(&a) || sensors.contains(&b))),
+    );
+    for (a, b) in collisions_missing_from(&contacts.pairs, &contacts.previous) {
+        started.send(CollisionStarted(a, b));
+    }
+    for (a, b) in collisions_missing_from(&contacts.previous, &contacts.pairs) {
+        ended.send(CollisionEnded(a, b));
+    }
+}
+
+/// The pairs without a `Sensor` in `pairs` but not in `other`. Maps iterate in no particular
+/// order, so they are sorted for events to be sent in the same order every run.
+fn collisions_missing_from(
+    pairs: &HashMap<(Entity, Entity), bool>,
+    other: &HashMap<(Entity, Entity), bool>,
+) -> Vec<(Entity, Entity)> {
+    sorted(
+        pairs
+            .iter()
+            .filter(|(pair, sensor)| !**sensor && !other.contains_key(*pair))
+            .map(|(pair, _)| pair),
+    )
+}
+
+pub(crate) fn sorted<'a, T: Ord + Copy + 'a>(items: impl Iterator<Item = &'a T>) -> Vec<T> {
+    let mut items: Vec<T> = items.copied().collect();
+    items.sort_unstable();
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-4;
+
+    /// A level with collision tiles at `walls`, on a floor wide enough for every test.
+    fn level(walls: impl Iterator<Item = Vec2>) -> Level {
+        let background = (-30..30)
+            .flat_map(|y| (-30..30).map(move |x| (Vec2::new(x, y), Vec2::new(0, 0))))
+            .collect();
+        Level::new("test")
+            .background_tiles(background)
+            .collision(walls.collect())
+            .build()
+    }
+
+    /// A wall one tile thick along column 5.
+    fn vertical_wall() -> Level {
+        level((-20..20).map(|y| Vec2::new(5, y)))
+    }
+
+    /// A wall one tile thick along row 5, long enough to slide along for a while.
+    fn horizontal_wall() -> Level {
+        level((-100..100).map(|x| Vec2::new(x, 5)))
+    }
+
+    // The default collider is 0.8 tiles wide and centred in its tile, so a body is flush against
+    // the near side of the wall at 4.1, and against the far side at 5.9.
+    const FLUSH_BEFORE: f32 = 4.1;
+    const FLUSH_AFTER: f32 = 5.9;
+    const DELTAS: [f32; 5] = [1.5, 2.5, 4.0, 10.5, 25.0];
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < EPSILON,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn stops_flush_moving_right() {
+        let level = vertical_wall();
+        for delta in DELTAS {
+            let start = Vec2F::new(FLUSH_BEFORE - delta / 2.0, 0.0);
+            let sweep = sweep_body(&level, &Collider::default(), start, Vec2F::new(delta, 0.0));
+            assert!(sweep.blocked_x && !sweep.blocked_y, "delta {}", delta);
+            assert_close(sweep.position.x, FLUSH_BEFORE);
+            assert_close(sweep.position.y, 0.0);
+        }
+    }
+
+    #[test]
+    fn stops_flush_moving_left() {
+        let level = vertical_wall();
+        for delta in DELTAS {
+            let start = Vec2F::new(FLUSH_AFTER + delta / 2.0, 0.0);
+            let sweep = sweep_body(&level, &Collider::default(), start, Vec2F::new(-delta, 0.0));
+            assert!(sweep.blocked_x && !sweep.blocked_y, "delta {}", delta);
+            assert_close(sweep.position.x, FLUSH_AFTER);
+            assert_close(sweep.position.y, 0.0);
+        }
+    }
+
+    #[test]
+    fn stops_flush_moving_down() {
+        let level = horizontal_wall();
+        for delta in DELTAS {
+            let start = Vec2F::new(0.0, FLUSH_BEFORE - delta / 2.0);
+            let sweep = sweep_body(&level, &Collider::default(), start, Vec2F::new(0.0, delta));
+            assert!(sweep.blocked_y && !sweep.blocked_x, "delta {}", delta);
+            assert_close(sweep.position.x, 0.0);
+            assert_close(sweep.position.y, FLUSH_BEFORE);
+        }
+    }
+
+    #[test]
+    fn stops_flush_moving_up() {
+        let level = horizontal_wall();
+        for delta in DELTAS {
+            let start = Vec2F::new(0.0, FLUSH_AFTER + delta / 2.0);
+            let sweep = sweep_body(&level, &Collider::default(), start, Vec2F::new(0.0, -delta));
+            assert!(sweep.blocked_y && !sweep.blocked_x, "delta {}", delta);
+            assert_close(sweep.position.x, 0.0);
+            assert_close(sweep.position.y, FLUSH_AFTER);
+        }
+    }
+
+    #[test]
+    fn never_tunnels_at_any_speed_or_frame_time() {
+        let level = vertical_wall();
+        let collider = Collider::default();
+        for speed in [1.0, 7.0, 30.0, 120.0, 1000.0] {
+            for frame_time in [1.0 / 240.0, 1.0 / 60.0, 1.0 / 10.0, 0.5] {
+                let delta = Vec2F::new(speed * frame_time, 0.0);
+                let mut pos = Vec2F::new(-10.0, 0.0);
+                let mut blocked = false;
+                for _ in 0..100_000 {
+                    let sweep = sweep_body(&level, &collider, pos, delta);
+                    pos = sweep.position;
+                    assert!(pos.x <= FLUSH_BEFORE + EPSILON, "went through at {}", pos.x);
+                    if sweep.blocked_x {
+                        blocked = true;
+                        break;
+                    }
+                }
+                assert!(blocked, "speed {} frame time {}", speed, frame_time);
+                assert_close(pos.x, FLUSH_BEFORE);
+            }
+        }
+    }
+
+    #[test]
+    fn slides_along_a_wall_without_snagging_on_seams() {
+        let level = horizontal_wall();
+        let collider = Collider::default();
+        for delta in [
+            Vec2F::new(0.37, 0.2),
+            Vec2F::new(-0.37, 0.2),
+            Vec2F::new(3.3, 1.0),
+        ] {
+            let mut pos = Vec2F::new(0.0, FLUSH_BEFORE);
+            for step in 1..=20 {
+                let sweep = sweep_body(&level, &collider, pos, delta);
+                assert!(!sweep.blocked_x, "snagged after {} steps", step);
+                assert!(sweep.blocked_y);
+                assert_close(sweep.position.x, pos.x + delta.x);
+                assert_close(sweep.position.y, FLUSH_BEFORE);
+                pos = sweep.position;
+            }
+        }
+    }
+
+    #[test]
+    fn circles_stop_against_walls_by_their_bounding_box() {
+        let level = vertical_wall();
+        let collider = Collider::circle(0.4);
+        // Grazing the corner at the end of the wall, which the circle itself would only touch
+        // about 0.2 tiles further on.
+        let start = Vec2F::new(0.0, 19.85);
+        let sweep = sweep_body(&level, &collider, start, Vec2F::new(10.0, 0.0));
+        assert!(sweep.blocked_x);
+        assert_close(sweep.position.x, FLUSH_BEFORE);
+    }
+
+    fn pairs(grid: &BroadphaseGrid) -> Vec<(Entity, Entity)> {
+        let mut pairs: Vec<_> = grid.overlapping_pairs().collect();
+        pairs.sort_unstable();
+        pairs
+    }
+
+    #[test]
+    fn broadphase_reports_each_overlap_once() {
+        let mut world = World::new();
+        let [a, b, c, d] = [(); 4].map(|_| world.spawn().id());
+        let mut grid = BroadphaseGrid::default();
+        // Two large boxes sharing many cells, and a circle touching the first of them.
+        grid.insert(a, Vec2F::new(0.0, 0.0), Collider::new_box(3.0, 3.0));
+        grid.insert(b, Vec2F::new(1.0, 1.0), Collider::new_box(3.0, 3.0));
+        grid.insert(c, Vec2F::new(-1.0, 0.0), Collider::circle(0.6));
+        // Far from everything else.
+        grid.insert(d, Vec2F::new(20.0, 20.0), Collider::default());
+        assert!(pairs(&grid) == vec![(a, b), (a, c)]);
+    }
+
+    #[test]
+    fn moves_freely_in_the_open() {
+        let level = level(std::iter::empty());
+        let sweep = sweep_body(
+            &level,
+            &Collider::default(),
+            Vec2F::new(1.0, 2.0),
+            Vec2F::new(-12.5, 7.25),
+        );
+        assert!(!sweep.blocked_x && !sweep.blocked_y);
+        assert_close(sweep.position.x, -11.5);
+        assert_close(sweep.position.y, 9.25);
+    }
+}
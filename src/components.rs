@@ -1,9 +1,12 @@
+use std::str::FromStr;
 use std::time::Duration;
 
 use bevy_ecs::prelude::*;
 use engine::timer::Timer;
 use engine::types::{Vec2, Vec2F};
 
+use crate::pathfinding::{Connectivity, Footprint, Heuristic};
+
 macro_rules! as_vec {
     ($name : ty, $wrapped : ty) => {
         impl $name {
@@ -64,6 +67,13 @@ as_vec!(Position, Vec2F);
 default_vec!(Position, Vec2F);
 new_vec!(Position, Vec2F, f32);
 
+/// The `Position` as of the previous tick, for drawing between ticks.
+#[derive(Component)]
+pub struct PreviousPosition(pub Vec2F);
+as_vec!(PreviousPosition, Vec2F);
+default_vec!(PreviousPosition, Vec2F);
+new_vec!(PreviousPosition, Vec2F, f32);
+
 #[derive(Component)]
 pub struct IntPosition(pub Vec2);
 as_vec!(IntPosition, Vec2);
@@ -81,6 +91,84 @@ pub struct Speed(pub f32);
 as_scalar!(Speed, f32);
 new_scalar!(Speed, f32);
 
+/// The width and height, in tiles, of a body that wasn't given a size: a tile, less a border so
+/// that it can slip past the corners of walls it only grazes.
+pub const DEFAULT_BODY_SIZE: f32 = 0.8;
+
+#[derive(Clone, Copy)]
+pub enum Shape {
+    /// An axis aligned box, by its half width and half height.
+    Box(Vec2F),
+    /// A circle, by its radius. Walls only see its bounding square.
+    Circle(f32),
+}
+
+/// The shape a body collides with, centered `offset` tiles from its position, which is the top
+/// left corner of the tile it stands on. Other bodies are collided with by the exact shape, but
+/// walls by its bounding box, so a circle stops against the corner of a wall like a square would.
+#[derive(Component, Clone, Copy)]
+pub struct Collider {
+    pub shape: Shape,
+    pub offset: Vec2F,
+}
+
+impl Collider {
+    /// A box centered on the tiles it covers when its position is on a tile.
+    pub fn new_box(width: f32, height: f32) -> Self {
+        Self {
+            shape: Shape::Box(Vec2F::new(width / 2.0, height / 2.0)),
+            offset: Self::centering_offset(width, height),
+        }
+    }
+    /// A circle centered on the tiles it covers when its position is on a tile.
+    pub fn circle(radius: f32) -> Self {
+        Self {
+            shape: Shape::Circle(radius),
+            offset: Self::centering_offset(radius * 2.0, radius * 2.0),
+        }
+    }
+    fn centering_offset(width: f32, height: f32) -> Vec2F {
+        Vec2F::new(width.ceil().max(1.0) / 2.0, height.ceil().max(1.0) / 2.0)
+    }
+    pub fn half_extents(&self) -> Vec2F {
+        match self.shape {
+            Shape::Box(half_extents) => half_extents,
+            Shape::Circle(radius) => Vec2F::new(radius, radius),
+        }
+    }
+    pub fn center(&self, pos: Vec2F) -> Vec2F {
+        Vec2F::new(pos.x + self.offset.x, pos.y + self.offset.y)
+    }
+    /// The top left and bottom right corners of the bounding box of the shape.
+    pub fn bounds(&self, pos: Vec2F) -> (Vec2F, Vec2F) {
+        let center = self.center(pos);
+        let half_extents = self.half_extents();
+        (
+            Vec2F::new(center.x - half_extents.x, center.y - half_extents.y),
+            Vec2F::new(center.x + half_extents.x, center.y + half_extents.y),
+        )
+    }
+    /// The tiles the body covers when its position is on a tile.
+    pub fn footprint(&self) -> Footprint {
+        let (min, max) = self.bounds(Vec2F::new(0.0, 0.0));
+        Footprint {
+            first: Vec2::new(min.x.floor() as i32, min.y.floor() as i32),
+            last: Vec2::new(max.x.ceil() as i32 - 1, max.y.ceil() as i32 - 1),
+        }
+    }
+}
+
+impl Default for Collider {
+    fn default() -> Self {
+        Self::new_box(DEFAULT_BODY_SIZE, DEFAULT_BODY_SIZE)
+    }
+}
+
+/// Marks a body that only notices what overlaps it, such as a `Trigger`. Its overlaps are kept in
+/// the `Contacts`, but send no `CollisionStarted` or `CollisionEnded`.
+#[derive(Component, Default)]
+pub struct Sensor;
+
 #[derive(Component, Default)]
 pub struct Player;
 
@@ -88,9 +176,11 @@ pub struct Player;
 pub struct PlayerBundle {
     pub player: Player,
     pub position: Position,
+    pub previous_position: PreviousPosition,
     pub int_position: IntPosition,
     pub velocity: Velocity,
     pub speed: Speed,
+    pub collider: Collider,
 }
 
 #[derive(Component)]
@@ -113,11 +203,137 @@ impl Default for Path {
     }
 }
 
+/// Asks for the `Path` of an entity to be filled with an A* path to `target`. Removed once
+/// handled.
+#[derive(Component)]
+pub struct PathRequest {
+    pub target: Vec2F,
+    pub heuristic: Heuristic,
+}
+
+/// Overrides the `Connectivity` of the level for the paths of this entity.
+#[derive(Component, Clone, Copy)]
+pub struct PathConnectivity(pub Connectivity);
+
+/// Paths of this entity are smoothed, skipping waypoints it can walk past in a straight line.
+#[derive(Component, Default)]
+pub struct SmoothPath;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PatrolMode {
+    /// Goes back to the first waypoint after the last.
+    Loop,
+    /// Walks the route backwards after the last waypoint, then forwards again.
+    PingPong,
+    /// Stops at the last waypoint.
+    Once,
+}
+
+impl FromStr for PatrolMode {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "loop" => Ok(Self::Loop),
+            "pingpong" => Ok(Self::PingPong),
+            "once" => Ok(Self::Once),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The direction an enemy or NPC was placed looking in.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Facing {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl FromStr for Facing {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "up" => Ok(Self::Up),
+            "down" => Ok(Self::Down),
+            "left" => Ok(Self::Left),
+            "right" => Ok(Self::Right),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A route of waypoints walked by feeding each leg to the `Path` of an enemy while it isn't
+/// chasing the player.
+#[derive(Component)]
+pub struct Patrol {
+    pub waypoints: Vec<Vec2F>,
+    pub mode: PatrolMode,
+    /// The waypoint being walked to, `None` once a `Once` route is done.
+    pub next: Option<usize>,
+    /// Whether a `PingPong` route is being walked backwards.
+    pub reversed: bool,
+    /// Whether the `Path` currently leads to the next waypoint.
+    pub on_route: bool,
+    /// Whether a `PathRequest` to the next waypoint is waiting to be answered.
+    pub requested: bool,
+}
+
+impl Patrol {
+    pub fn new(waypoints: Vec<Vec2F>, mode: PatrolMode) -> Self {
+        let next = if waypoints.is_empty() { None } else { Some(0) };
+        Self {
+            waypoints,
+            mode,
+            next,
+            reversed: false,
+            on_route: false,
+            requested: false,
+        }
+    }
+    pub fn next_waypoint(&self) -> Option<Vec2F> {
+        self.next.map(|next| self.waypoints[next])
+    }
+    /// Moves on to the waypoint after the next one, as the mode dictates.
+    pub fn advance(&mut self) {
+        let last = self.waypoints.len().saturating_sub(1);
+        self.next = match (self.next, self.mode) {
+            (None, _) => None,
+            (Some(next), _) if last == 0 => Some(next),
+            (Some(next), PatrolMode::Loop) => Some(if next == last { 0 } else { next + 1 }),
+            (Some(next), PatrolMode::Once) => (next < last).then(|| next + 1),
+            (Some(next), PatrolMode::PingPong) => {
+                if (self.reversed && next == 0) || (!self.reversed && next == last) {
+                    self.reversed = !self.reversed;
+                }
+                Some(if self.reversed { next - 1 } else { next + 1 })
+            }
+        };
+    }
+}
+
+/// How close, in tiles, the player has to be in sight for an enemy to start chasing them.
 #[derive(Component, Default)]
 pub struct AggroDistance(pub f32);
 as_scalar!(AggroDistance, f32);
 new_scalar!(AggroDistance, f32);
 
+/// How far, in tiles, the player has to get away from a chasing enemy for it to give up. Kept
+/// larger than the `AggroDistance`, so that enemies don't flicker in and out of a chase.
+#[derive(Component, Default)]
+pub struct LeashDistance(pub f32);
+as_scalar!(LeashDistance, f32);
+new_scalar!(LeashDistance, f32);
+
+/// The `LeashDistance` of an enemy that wasn't given one, relative to its `AggroDistance`.
+pub const LEASH_FACTOR: f32 = 1.5;
+
+/// Whether an enemy is chasing the player.
+#[derive(Component, Default)]
+pub struct Aggroed(pub bool);
+as_scalar!(Aggroed, bool);
+new_scalar!(Aggroed, bool);
+
 #[derive(Component, Default)]
 pub struct Enemy;
 
@@ -127,23 +343,62 @@ pub struct Dumb;
 #[derive(Component, Default)]
 pub struct Smart;
 
-#[derive(Bundle, Default)]
+/// The id of the dialogue an NPC starts when talked to.
+#[derive(Component)]
+pub struct Dialogue(pub String);
+
+#[derive(Bundle)]
 pub struct EnemyBundle {
     pub enemy: Enemy,
     pub position: Position,
+    pub previous_position: PreviousPosition,
     pub velocity: Velocity,
     pub speed: Speed,
+    pub collider: Collider,
     pub path: Path,
     pub aggro_distance: AggroDistance,
+    pub leash_distance: LeashDistance,
+    pub aggroed: Aggroed,
 }
 
-#[derive(Bundle, Default)]
+impl Default for EnemyBundle {
+    fn default() -> Self {
+        Self {
+            enemy: Enemy,
+            position: Position::default(),
+            previous_position: PreviousPosition::default(),
+            velocity: Velocity::default(),
+            speed: Speed::new(2.0),
+            collider: Collider::default(),
+            path: Path::default(),
+            aggro_distance: AggroDistance::new(3.5),
+            leash_distance: LeashDistance::new(3.5 * LEASH_FACTOR),
+            aggroed: Aggroed::default(),
+        }
+    }
+}
+
+#[derive(Bundle)]
 pub struct SmartEnemyBundle {
     #[bundle]
     pub enemy: EnemyBundle,
     pub pathfinding: Smart,
 }
 
+/// Smart enemies can path around walls, so they notice the player from further away.
+impl Default for SmartEnemyBundle {
+    fn default() -> Self {
+        Self {
+            enemy: EnemyBundle {
+                aggro_distance: AggroDistance::new(10.5),
+                leash_distance: LeashDistance::new(10.5 * LEASH_FACTOR),
+                ..Default::default()
+            },
+            pathfinding: Smart,
+        }
+    }
+}
+
 #[derive(Bundle, Default)]
 pub struct DumbEnemyBundle {
     #[bundle]
@@ -154,6 +409,8 @@ pub struct DumbEnemyBundle {
 #[derive(Component, Default)]
 pub struct Camera {
     pub offset: Vec2F,
+    /// The `offset` as of the previous tick, for drawing between ticks.
+    pub previous_offset: Vec2F,
 }
 
 #[derive(Bundle, Default)]
@@ -161,3 +418,83 @@ pub struct CameraBundle {
     pub camera: Camera,
     pub position: Position,
 }
+
+/// Marks entities spawned from the current level, which are despawned when it is unloaded.
+#[derive(Component, Default)]
+pub struct LevelEntity;
+
+/// Sends the player to another level when touched. A `target` of `None` means the next level.
+#[derive(Component, Default)]
+pub struct Door {
+    pub target: Option<String>,
+    pub spawn: Option<String>,
+}
+
+#[derive(Bundle, Default)]
+pub struct DoorBundle {
+    pub door: Door,
+    pub position: Position,
+}
+
+#[derive(Component, Default)]
+pub struct SpawnPoint {
+    pub name: String,
+}
+
+#[derive(Bundle, Default)]
+pub struct SpawnPointBundle {
+    pub spawn_point: SpawnPoint,
+    pub position: Position,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    fn assert_bounds(collider: &Collider, expected: (f32, f32, f32, f32)) {
+        let (min, max) = collider.bounds(Vec2F::new(0.0, 0.0));
+        let actual = (min.x, min.y, max.x, max.y);
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-5;
+        assert!(
+            close(actual.0, expected.0)
+                && close(actual.1, expected.1)
+                && close(actual.2, expected.2)
+                && close(actual.3, expected.3),
+            "expected {:?}, got {:?}",
+            expected,
+            actual
+        );
+    }
+
+    /// The tiles a footprint covers when standing on the origin, in the order it checks them.
+    fn tiles(footprint: Footprint) -> Vec<(i32, i32)> {
+        let tiles = RefCell::new(Vec::new());
+        footprint.fits(Vec2::new(0, 0), |tile| {
+            tiles.borrow_mut().push((tile.x, tile.y));
+            true
+        });
+        tiles.into_inner()
+    }
+
+    #[test]
+    fn default_collider_is_centered_in_its_tile() {
+        let collider = Collider::default();
+        assert_bounds(&collider, (0.1, 0.1, 0.9, 0.9));
+        assert_eq!(tiles(collider.footprint()), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn larger_colliders_are_centered_on_the_tiles_they_cover() {
+        let collider = Collider::new_box(1.8, 2.6);
+        assert_bounds(&collider, (0.1, 0.2, 1.9, 2.8));
+        assert_eq!(
+            tiles(collider.footprint()),
+            vec![(0, 0), (1, 0), (0, 1), (1, 1), (0, 2), (1, 2)]
+        );
+        let circle = Collider::circle(0.75);
+        assert_bounds(&circle, (0.25, 0.25, 1.75, 1.75));
+        assert_eq!(tiles(circle.footprint()).len(), 4);
+    }
+}
@@ -0,0 +1,271 @@
+//! Support for running the game simulation deterministically, for replays and regression tests.
+//! Given a seed, the simulation runs on fixed ticks, one system at a time, and every two of its
+//! systems that touch the same data are ordered against each other by `fixed_schedule`, so the
+//! same input always gives bit-identical results, which `world_hash` can check.
+//! Systems that go over entities in parallel only write to the entity they are on, and events
+//! built from sets are sorted before they are sent, so neither depends on thread timing or hash
+//! order.
+//!
+//! The promise holds tick for tick, not frame for frame: how many ticks a frame runs depends on
+//! how long it took, and time past `MAX_TICKS_PER_FRAME` ticks is dropped. Two runs can only be
+//! compared after the same number of ticks with the same input on each.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bevy_ecs::prelude::*;
+use engine::types::Vec2F;
+
+use crate::ai::StateMachine;
+use crate::collision::Contacts;
+use crate::components::*;
+
+/// The source of any randomness in the simulation, a SplitMix64 generator. Seeded from the
+/// manifest in deterministic mode, and from the clock otherwise. Nothing draws from it yet, but
+/// systems that need randomness must, so that seeded runs stay reproducible. Its state is part of
+/// `world_hash`, so a run that draws from it a different number of times hashes differently.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+    pub fn from_clock() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64);
+        Self::new(seed)
+    }
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+    /// A number from 0 up to but not including 1.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+    /// A number from `min` up to but not including `max`.
+    pub fn range(&mut self, min: f32, max: f32) -> f32 {
+        min + (max - min) * self.next_f32()
+    }
+}
+
+/// 64-bit FNV-1a, written out rather than taken from `std` so that hashes are the same across
+/// builds, Rust versions and platforms, and can be stored alongside replays.
+struct WorldHasher(u64);
+
+impl WorldHasher {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    fn new() -> Self {
+        Self(Self::OFFSET_BASIS)
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(Self::PRIME);
+        }
+    }
+    fn write_u8(&mut self, value: u8) {
+        self.write(&[value]);
+    }
+    fn write_u32(&mut self, value: u32) {
+        self.write(&value.to_le_bytes());
+    }
+    fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+    fn write_vec(&mut self, vec: Vec2F) {
+        self.write_u32(vec.x.to_bits());
+        self.write_u32(vec.y.to_bits());
+    }
+    /// Whether an optional value is there, so that fields following a missing one can't be
+    /// mistaken for it.
+    fn write_some<T>(&mut self, option: &Option<T>) {
+        self.write_u8(option.is_some() as u8);
+    }
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Hashes the state of the simulation: the bodies, their paths and AI, the contacts between them
+/// and the `Rng`. Entities are hashed in order, so two runs from the same seed and input hash the
+/// same after the same number of ticks. Lists are hashed with their length and optional values
+/// with whether they are there, so different states can't run together into the same bytes.
+pub fn world_hash(world: &mut World) -> u64 {
+    let mut hasher = WorldHasher::new();
+    let mut bodies: Vec<_> = world
+        .query::<(
+            Entity,
+            &Position,
+            Option<&Velocity>,
+            Option<&Path>,
+            Option<&Aggroed>,
+            Option<&StateMachine>,
+        )>()
+        .iter(world)
+        .collect();
+    bodies.sort_unstable_by_key(|(entity, ..)| *entity);
+    hasher.write_u64(bodies.len() as u64);
+    for (entity, pos, vel, path, aggroed, machine) in bodies {
+        hasher.write_u32(entity.id());
+        hasher.write_u32(entity.generation());
+        hasher.write_vec(pos.0);
+        hasher.write_some(&vel);
+        if let Some(vel) = vel {
+            hasher.write_vec(vel.0);
+        }
+        hasher.write_some(&path);
+        if let Some(path) = path {
+            hasher.write_u64(path.points.len() as u64);
+            for point in &path.points {
+                hasher.write_vec(*point);
+            }
+            hasher.write_some(&path.next_point);
+            if let Some(next_point) = path.next_point {
+                hasher.write_vec(next_point);
+            }
+        }
+        hasher.write_some(&aggroed);
+        if let Some(aggroed) = aggroed {
+            hasher.write_u8(aggroed.0 as u8);
+        }
+        hasher.write_some(&machine);
+        if let Some(machine) = machine {
+            hasher.write_u8(machine.state as u8);
+            hasher.write_u64(machine.elapsed.as_secs());
+            hasher.write_u32(machine.elapsed.subsec_nanos());
+        }
+    }
+    let contacts = world.get_resource::<Contacts>();
+    hasher.write_some(&contacts);
+    if let Some(contacts) = contacts {
+        let mut pairs: Vec<_> = contacts.iter().collect();
+        pairs.sort_unstable();
+        hasher.write_u64(pairs.len() as u64);
+        for (a, b) in pairs {
+            hasher.write_u32(a.id());
+            hasher.write_u32(b.id());
+        }
+    }
+    let rng = world.get_resource::<Rng>();
+    hasher.write_some(&rng);
+    if let Some(rng) = rng {
+        hasher.write_u64(rng.state);
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path as FilePath;
+
+    use engine::types::{Vec2, Vec2F, VirtualKeyCode, WinitInputHelper};
+
+    use super::*;
+    use crate::ai::Behaviors;
+    use crate::entity_def::EntityDef;
+    use crate::file::read_level;
+    use crate::level_manager::LevelManager;
+    use crate::pathfinding::is_walkable;
+    use crate::resources::*;
+    use crate::schedule::{fixed_schedule, insert_simulation_resources};
+    use crate::spawn::{spawn_level_entities, EntityRegistry};
+    use crate::systems::reset_interpolation;
+    use crate::GameRunMode;
+
+    const TICKS: usize = 240;
+
+    /// `level_1` with a few enemies around the player, set up the way `Game` does.
+    fn game_world(seed: u64) -> World {
+        let mut world = World::new();
+        world.spawn().insert_bundle(CameraBundle::default());
+        insert_simulation_resources(&mut world);
+        world.insert_resource(EntityRegistry::default());
+        world.insert_resource(Behaviors::default());
+        world.insert_resource(ControlBindings::default());
+        let controls = world.resource::<ControlBindings>();
+        let movement_bindings: Vec<VirtualKeyCode> = [
+            &controls.up,
+            &controls.down,
+            &controls.left,
+            &controls.right,
+        ]
+        .into_iter()
+        .flatten()
+        .copied()
+        .collect();
+        world.insert_resource(movement_bindings);
+        world.insert_resource(WinitInputHelper::new());
+        world.insert_resource(GameRunMode::Game);
+        world.insert_resource(Screen {
+            dim: Vec2::new(320, 240),
+        });
+        world.insert_resource(TileMeta {
+            dim: DEFAULT_TILE_DIM,
+            visible: Vec2::new(0, 0),
+            offset: Vec2F::new(0.0, 0.0),
+        });
+        world.insert_resource(FixedTimestep::default().tick);
+        world.insert_resource(Rng::new(seed));
+        world.insert_resource(LevelManager::new(Vec::new()));
+        let path = FilePath::new(env!("CARGO_MANIFEST_DIR")).join("resources/maps/level_1.lvl");
+        let level = read_level(&path, "level_1").unwrap().build();
+        let (start, _) = level.entities_of_kind("player_start").next().unwrap();
+        let mut spots: Vec<Vec2> = (start.y - 8..=start.y + 8)
+            .flat_map(|y| (start.x - 8..=start.x + 8).map(move |x| Vec2::new(x, y)))
+            .filter(|pos| {
+                let distance = (pos.x - start.x).abs() + (pos.y - start.y).abs();
+                distance >= 4 && is_walkable(&level, *pos)
+            })
+            .collect();
+        spots.sort_unstable_by_key(|pos| (pos.y, pos.x));
+        world.insert_resource(level);
+        assert!(spawn_level_entities(&mut world).is_empty());
+        let enemies = ["enemy_smart aggro=20", "enemy_dumb aggro=20"];
+        world.resource_scope(|world, registry: Mut<EntityRegistry>| {
+            for (pos, label) in spots.iter().step_by(3).zip(enemies.iter().cycle()).take(8) {
+                let def = EntityDef::parse(label).unwrap();
+                let pos = Vec2F::new(pos.x as f32, pos.y as f32);
+                registry.spawn(world, pos, &def).unwrap();
+            }
+        });
+        reset_interpolation(&mut world);
+        world
+    }
+
+    /// Hashes the world after each of `TICKS` ticks.
+    fn run(seed: u64) -> Vec<u64> {
+        let mut world = game_world(seed);
+        // `par_for_each_mut` needs the task pool that parallel stages set up.
+        SystemStage::parallel().with_system(|| {}).run(&mut world);
+        let mut schedule = fixed_schedule(true);
+        (0..TICKS)
+            .map(|_| {
+                schedule.run(&mut world);
+                world_hash(&mut world)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn runs_from_the_same_seed_hash_the_same() {
+        let first = run(7);
+        assert_eq!(first, run(7));
+        // The enemies moved, so the runs compared more than the starting state.
+        assert_ne!(first[0], first[TICKS - 1]);
+    }
+
+    #[test]
+    fn fnv1a_matches_reference_values() {
+        let mut hasher = WorldHasher::new();
+        assert_eq!(hasher.finish(), 0xcbf2_9ce4_8422_2325);
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63_dc4c_8601_ec8c);
+    }
+}
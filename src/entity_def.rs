@@ -0,0 +1,284 @@
+//! Level entities are stored as a single UTF-8 label: a kind followed by whitespace separated
+//! `key=value` properties, for example `enemy_smart speed=3.5 aggro=8 facing=left`.
+//! Values can't contain whitespace.
+//!
+//! Patrol routes are made of waypoint entities with kinds of the form `patrol:<route>:<index>`,
+//! followed in order of index by the enemies with a matching `patrol=<route>`.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::components::{Collider, Facing, PatrolMode, DEFAULT_BODY_SIZE};
+use crate::pathfinding::Connectivity;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EntityDefError {
+    MissingKind,
+    MalformedProperty(String),
+    DuplicateProperty(String),
+    InvalidValue { key: String, value: String },
+    InvalidPatrolWaypoint(String),
+}
+
+impl fmt::Display for EntityDefError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingKind => write!(f, "entity label is empty"),
+            Self::MalformedProperty(token) => {
+                write!(f, "property \"{}\" is not of the form key=value", token)
+            }
+            Self::DuplicateProperty(key) => write!(f, "property \"{}\" is set twice", key),
+            Self::InvalidValue { key, value } => {
+                write!(f, "invalid value \"{}\" for property \"{}\"", value, key)
+            }
+            Self::InvalidPatrolWaypoint(kind) => write!(
+                f,
+                "patrol waypoint \"{}\" is not of the form patrol:<route>:<index>",
+                kind
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EntityDefError {}
+
+/// Only made by `parse`, so the properties with known types always parse and their accessors
+/// can be unwrapped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntityDef {
+    kind: String,
+    /// Properties in the order they were written.
+    properties: Vec<(String, String)>,
+}
+
+impl EntityDef {
+    fn new(kind: &str) -> Self {
+        Self {
+            kind: kind.to_owned(),
+            properties: Vec::new(),
+        }
+    }
+    pub fn parse(label: &str) -> Result<Self, EntityDefError> {
+        let mut tokens = label.split_whitespace();
+        let kind = tokens.next().ok_or(EntityDefError::MissingKind)?;
+        let mut def = Self::new(kind);
+        for token in tokens {
+            let (key, value) = token
+                .split_once('=')
+                .filter(|(key, value)| !key.is_empty() && !value.is_empty())
+                .ok_or_else(|| EntityDefError::MalformedProperty(token.to_owned()))?;
+            if def.get(key).is_some() {
+                return Err(EntityDefError::DuplicateProperty(key.to_owned()));
+            }
+            def.properties.push((key.to_owned(), value.to_owned()));
+        }
+        def.validate()?;
+        Ok(def)
+    }
+    /// Checks that the properties with known types parse.
+    fn validate(&self) -> Result<(), EntityDefError> {
+        if self.kind.starts_with("patrol:") && self.patrol_waypoint().is_none() {
+            return Err(EntityDefError::InvalidPatrolWaypoint(self.kind.clone()));
+        }
+        self.speed()?;
+        self.aggro_distance()?;
+        self.leash_distance()?;
+        self.repath_interval()?;
+        self.facing()?;
+        self.connectivity()?;
+        self.smooth_path()?;
+        self.patrol_mode()?;
+        self.collider()?;
+        Ok(())
+    }
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+    /// Properties in the order they were written.
+    pub fn properties(&self) -> &[(String, String)] {
+        &self.properties
+    }
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.properties
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+    pub fn get_parsed<T: FromStr>(&self, key: &str) -> Result<Option<T>, EntityDefError> {
+        match self.get(key) {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| EntityDefError::InvalidValue {
+                    key: key.to_owned(),
+                    value: value.to_owned(),
+                }),
+            None => Ok(None),
+        }
+    }
+    fn get_non_negative(&self, key: &str) -> Result<Option<f32>, EntityDefError> {
+        match self.get_parsed::<f32>(key)? {
+            Some(value) if !value.is_finite() || value < 0.0 => Err(EntityDefError::InvalidValue {
+                key: key.to_owned(),
+                value: value.to_string(),
+            }),
+            value => Ok(value),
+        }
+    }
+    /// `speed`, in tiles per second.
+    pub fn speed(&self) -> Result<Option<f32>, EntityDefError> {
+        self.get_non_negative("speed")
+    }
+    /// `aggro`, in tiles.
+    pub fn aggro_distance(&self) -> Result<Option<f32>, EntityDefError> {
+        self.get_non_negative("aggro")
+    }
+    /// `leash`, in tiles.
+    pub fn leash_distance(&self) -> Result<Option<f32>, EntityDefError> {
+        self.get_non_negative("leash")
+    }
+    /// `repath`, the seconds between path rebuilds.
+    pub fn repath_interval(&self) -> Result<Option<Duration>, EntityDefError> {
+        Ok(self
+            .get_non_negative("repath")?
+            .map(Duration::from_secs_f32))
+    }
+    /// `facing`, one of `up`, `down`, `left` or `right`.
+    pub fn facing(&self) -> Result<Option<Facing>, EntityDefError> {
+        self.get_parsed("facing")
+    }
+    /// `connectivity`, `4` or `8`, overriding the level's for this entity's paths.
+    pub fn connectivity(&self) -> Result<Option<Connectivity>, EntityDefError> {
+        self.get_parsed("connectivity")
+    }
+    /// `smooth`, `true` to cut the corners of paths where there is room to.
+    pub fn smooth_path(&self) -> Result<Option<bool>, EntityDefError> {
+        self.get_parsed("smooth")
+    }
+    /// `behavior`, the name of the manifest behavior an NPC follows.
+    pub fn behavior(&self) -> Option<&str> {
+        self.get("behavior")
+    }
+    /// `patrol`, the id of the patrol route to follow.
+    pub fn patrol_route(&self) -> Option<&str> {
+        self.get("patrol")
+    }
+    /// `patrol_mode`, one of `loop`, `pingpong` or `once`.
+    pub fn patrol_mode(&self) -> Result<Option<PatrolMode>, EntityDefError> {
+        self.get_parsed("patrol_mode")
+    }
+    /// The route and index of a patrol waypoint.
+    pub fn patrol_waypoint(&self) -> Option<(&str, u32)> {
+        let (route, index) = self.kind.strip_prefix("patrol:")?.split_once(':')?;
+        if route.is_empty() {
+            return None;
+        }
+        Some((route, index.parse().ok()?))
+    }
+    /// `width`, in tiles.
+    pub fn width(&self) -> Result<Option<f32>, EntityDefError> {
+        self.get_non_negative("width")
+    }
+    /// `height`, in tiles.
+    pub fn height(&self) -> Result<Option<f32>, EntityDefError> {
+        self.get_non_negative("height")
+    }
+    /// A circle of `radius` tiles, or else a box `width` by `height` tiles, taking the default
+    /// size for a side that isn't given.
+    pub fn collider(&self) -> Result<Option<Collider>, EntityDefError> {
+        if let Some(radius) = self.get_non_negative("radius")? {
+            return Ok(Some(Collider::circle(radius)));
+        }
+        let width = self.width()?;
+        let height = self.height()?;
+        if width.is_none() && height.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Collider::new_box(
+            width.unwrap_or(DEFAULT_BODY_SIZE),
+            height.unwrap_or(DEFAULT_BODY_SIZE),
+        )))
+    }
+    /// `dialogue`, the id of the dialogue to start when talked to.
+    pub fn dialogue_id(&self) -> Option<&str> {
+        self.get("dialogue")
+    }
+    /// `name`, used to refer to spawn points and triggers.
+    pub fn name(&self) -> Option<&str> {
+        self.get("name")
+    }
+    /// `target`, the id of the level a door leads to.
+    pub fn target_level(&self) -> Option<&str> {
+        self.get("target")
+    }
+    /// `spawn`, the name of the spawn point a door places the player at.
+    pub fn target_spawn(&self) -> Option<&str> {
+        self.get("spawn")
+    }
+}
+
+/// Formats the entity back into its label.
+impl fmt::Display for EntityDef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        for (key, value) in &self.properties {
+            write!(f, " {}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_kind_and_properties_in_order() {
+        let def = EntityDef::parse("enemy_smart speed=3.5 aggro=8 facing=left").unwrap();
+        assert_eq!(def.kind(), "enemy_smart");
+        assert_eq!(def.speed(), Ok(Some(3.5)));
+        assert_eq!(def.aggro_distance(), Ok(Some(8.0)));
+        assert_eq!(def.facing(), Ok(Some(Facing::Left)));
+        assert_eq!(def.to_string(), "enemy_smart speed=3.5 aggro=8 facing=left");
+    }
+
+    #[test]
+    fn rejects_invalid_typed_properties() {
+        assert_eq!(
+            EntityDef::parse("enemy_dumb speed=fast"),
+            Err(EntityDefError::InvalidValue {
+                key: "speed".to_owned(),
+                value: "fast".to_owned(),
+            })
+        );
+        assert!(EntityDef::parse("enemy_dumb speed=-1").is_err());
+        assert!(EntityDef::parse("trigger width=wide").is_err());
+        assert!(EntityDef::parse("npc radius=-0.5").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_labels() {
+        assert_eq!(EntityDef::parse("  "), Err(EntityDefError::MissingKind));
+        assert_eq!(
+            EntityDef::parse("npc speed"),
+            Err(EntityDefError::MalformedProperty("speed".to_owned()))
+        );
+        assert_eq!(
+            EntityDef::parse("npc speed=1 speed=2"),
+            Err(EntityDefError::DuplicateProperty("speed".to_owned()))
+        );
+        assert_eq!(
+            EntityDef::parse("patrol:route"),
+            Err(EntityDefError::InvalidPatrolWaypoint(
+                "patrol:route".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn reads_patrol_waypoints() {
+        let def = EntityDef::parse("patrol:gate:2").unwrap();
+        assert_eq!(def.patrol_waypoint(), Some(("gate", 2)));
+    }
+}
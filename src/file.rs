@@ -1,84 +1,886 @@
+//! Level files come in two layouts, both little-endian, with positions stored as an `i64` pair
+//! with Y pointing up.
+//!
+//! v1 (legacy), as written by older versions of the level editor:
+//! - `u64` background length, `u64` foreground length, `u64` collision length
+//! - background tiles, then foreground tiles: `i64` x, `i64` y, `i16` spritesheet x and y
+//! - collision tiles: `i64` x, `i64` y
+//! - entities until the end of the file: `i64` x, `i64` y, `u64` label length, UTF-8 label
+//!
+//! Entity labels carry their properties, see `entity_def`.
+//!
+//! v2:
+//! - the magic bytes `RSLV`, then a `u16` format version
+//! - chunks until the end of the file: a 4 byte tag, a `u64` payload length, the payload
+//!
+//! The `BGND`, `FGND`, `COLL` and `ENTS` chunk payloads use the same records as the matching v1
+//! sections. `META` holds key/value pairs, each a `u64` length and UTF-8 string, including the
+//! `tile_size` and the pathfinding `connectivity`. Readers skip chunks with tags they don't
+//! recognize, so new data can be added in new chunks; the format version is only bumped when an
+//! existing chunk changes layout.
+
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use engine::resource::ImageHandle;
 use engine::types::Vec2;
 
-use crate::resources::Level;
+use crate::entity_def::{EntityDef, EntityDefError};
+use crate::pathfinding::{Connectivity, CONNECTIVITY_KEY};
+use crate::resources::{format_tile_size, parse_tile_size, Level, LevelBuilder, TILE_SIZE_KEY};
+use crate::tile_grid::TileGrid;
+
+pub const MAGIC: [u8; 4] = *b"RSLV";
+pub const FORMAT_VERSION: u16 = 2;
+
+const TAG_BACKGROUND: [u8; 4] = *b"BGND";
+const TAG_FOREGROUND: [u8; 4] = *b"FGND";
+const TAG_COLLISION: [u8; 4] = *b"COLL";
+const TAG_ENTITIES: [u8; 4] = *b"ENTS";
+const TAG_METADATA: [u8; 4] = *b"META";
+/// The chunks this reader understands. Any of them may only appear once; other chunks are
+/// skipped, however often they appear.
+const KNOWN_TAGS: [[u8; 4]; 5] = [
+    TAG_BACKGROUND,
+    TAG_FOREGROUND,
+    TAG_COLLISION,
+    TAG_ENTITIES,
+    TAG_METADATA,
+];
+
+const HEADER_LEN: usize = 8 * 3;
+const V2_HEADER_LEN: usize = 4 + 2;
+const CHUNK_HEADER_LEN: usize = 4 + 8;
+const TILE_STRIDE_LEN: usize = 8 + 8 + 2 + 2;
+const COLLISION_STRIDE_LEN: usize = 8 + 8;
+const ENTITY_HEADER_LEN: usize = 8 + 8 + 8;
+
+#[derive(Debug)]
+pub enum LevelLoadError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    TruncatedHeader {
+        expected: usize,
+        len: usize,
+    },
+    SectionOutOfBounds {
+        section: &'static str,
+        offset: usize,
+        len: u64,
+        file_len: usize,
+    },
+    BadSectionStride {
+        section: &'static str,
+        offset: usize,
+        len: u64,
+        stride: usize,
+    },
+    CoordinateOutOfRange {
+        offset: usize,
+        value: i64,
+    },
+    TruncatedEntity {
+        offset: usize,
+    },
+    InvalidEntityLabel {
+        offset: usize,
+        source: std::string::FromUtf8Error,
+    },
+    InvalidEntityProperties {
+        offset: usize,
+        source: EntityDefError,
+    },
+    DuplicatePlayerStart {
+        offset: usize,
+    },
+    DuplicateEntity {
+        offset: usize,
+        x: i32,
+        y: i32,
+    },
+    NoBackgroundTiles,
+    InvalidTileSize {
+        offset: usize,
+        value: String,
+    },
+    InvalidConnectivity {
+        offset: usize,
+        value: String,
+    },
+    UnsupportedVersion {
+        version: u16,
+    },
+    TruncatedChunk {
+        offset: usize,
+    },
+    DuplicateChunk {
+        tag: [u8; 4],
+        offset: usize,
+    },
+    InvalidMetadata {
+        offset: usize,
+        source: std::string::FromUtf8Error,
+    },
+}
+
+impl fmt::Display for LevelLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, source } => {
+                write!(f, "couldn't read {}: {}", path.display(), source)
+            }
+            Self::TruncatedHeader { expected, len } => write!(
+                f,
+                "truncated header: expected {} bytes, file is {} bytes",
+                expected, len
+            ),
+            Self::SectionOutOfBounds {
+                section,
+                offset,
+                len,
+                file_len,
+            } => write!(
+                f,
+                "{} section at byte {} claims {} bytes, file is {} bytes",
+                section, offset, len, file_len
+            ),
+            Self::BadSectionStride {
+                section,
+                offset,
+                len,
+                stride,
+            } => write!(
+                f,
+                "{} section at byte {} is {} bytes, not a multiple of {}",
+                section, offset, len, stride
+            ),
+            Self::CoordinateOutOfRange { offset, value } => {
+                write!(
+                    f,
+                    "coordinate {} at byte {} does not fit in i32",
+                    value, offset
+                )
+            }
+            Self::TruncatedEntity { offset } => {
+                write!(f, "truncated entity record at byte {}", offset)
+            }
+            Self::InvalidEntityLabel { offset, source } => {
+                write!(f, "invalid entity label at byte {}: {}", offset, source)
+            }
+            Self::InvalidEntityProperties { offset, source } => {
+                write!(f, "invalid entity label at byte {}: {}", offset, source)
+            }
+            Self::DuplicatePlayerStart { offset } => {
+                write!(f, "second player_start entity at byte {}", offset)
+            }
+            Self::DuplicateEntity { offset, x, y } => {
+                write!(f, "second entity at ({}, {}) at byte {}", x, y, offset)
+            }
+            Self::NoBackgroundTiles => write!(f, "level has no background tiles"),
+            Self::InvalidTileSize { offset, value } => write!(
+                f,
+                "invalid tile size \"{}\" in metadata at byte {}",
+                value, offset
+            ),
+            Self::InvalidConnectivity { offset, value } => write!(
+                f,
+                "invalid connectivity \"{}\" in metadata at byte {}, expected 4 or 8",
+                value, offset
+            ),
+            Self::UnsupportedVersion { version } => write!(
+                f,
+                "unsupported format version {}, expected {}",
+                version, FORMAT_VERSION
+            ),
+            Self::TruncatedChunk { offset } => write!(f, "truncated chunk at byte {}", offset),
+            Self::DuplicateChunk { tag, offset } => write!(
+                f,
+                "duplicate {} chunk at byte {}",
+                String::from_utf8_lossy(tag),
+                offset
+            ),
+            Self::InvalidMetadata { offset, source } => {
+                write!(f, "invalid metadata at byte {}: {}", offset, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LevelLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            Self::InvalidEntityLabel { source, .. } => Some(source),
+            Self::InvalidEntityProperties { source, .. } => Some(source),
+            Self::InvalidMetadata { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_i16(buf: &[u8], offset: usize) -> i16 {
+    i16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_coord(buf: &[u8], offset: usize) -> Result<i32, LevelLoadError> {
+    let value = i64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
+    i32::try_from(value).map_err(|_| LevelLoadError::CoordinateOutOfRange { offset, value })
+}
+
+/// The file stores Y pointing up, the game stores Y pointing down.
+fn read_position(buf: &[u8], offset: usize) -> Result<Vec2, LevelLoadError> {
+    let x = read_coord(buf, offset)?;
+    let y = read_coord(buf, offset + 8)?;
+    let y = y
+        .checked_neg()
+        .ok_or(LevelLoadError::CoordinateOutOfRange {
+            offset: offset + 8,
+            value: y as i64,
+        })?;
+    Ok(Vec2::new(x, y))
+}
+
+fn section<'a>(
+    buf: &'a [u8],
+    name: &'static str,
+    offset: usize,
+    len: u64,
+    stride: usize,
+) -> Result<&'a [u8], LevelLoadError> {
+    let out_of_bounds = || LevelLoadError::SectionOutOfBounds {
+        section: name,
+        offset,
+        len,
+        file_len: buf.len(),
+    };
+    let end = usize::try_from(len)
+        .ok()
+        .and_then(|len| offset.checked_add(len))
+        .filter(|end| *end <= buf.len())
+        .ok_or_else(out_of_bounds)?;
+    if len % stride as u64 != 0 {
+        return Err(LevelLoadError::BadSectionStride {
+            section: name,
+            offset,
+            len,
+            stride,
+        });
+    }
+    Ok(&buf[offset..end])
+}
+
+fn read_tiles(
+    buf: &[u8],
+    name: &'static str,
+    offset: usize,
+    len: u64,
+) -> Result<HashMap<Vec2, Vec2>, LevelLoadError> {
+    let bytes = section(buf, name, offset, len, TILE_STRIDE_LEN)?;
+    let mut tiles = HashMap::with_capacity(bytes.len() / TILE_STRIDE_LEN);
+    for (i, chunk) in bytes.chunks_exact(TILE_STRIDE_LEN).enumerate() {
+        let pos = read_position(buf, offset + i * TILE_STRIDE_LEN)?;
+        let row = read_i16(chunk, 16);
+        let col = read_i16(chunk, 18);
+        tiles.insert(pos, Vec2::new(row as i32, col as i32));
+    }
+    Ok(tiles)
+}
+
+fn read_collision(buf: &[u8], offset: usize, len: u64) -> Result<HashSet<Vec2>, LevelLoadError> {
+    let bytes = section(buf, "collision", offset, len, COLLISION_STRIDE_LEN)?;
+    let mut collision = HashSet::with_capacity(bytes.len() / COLLISION_STRIDE_LEN);
+    for i in 0..bytes.len() / COLLISION_STRIDE_LEN {
+        collision.insert(read_position(buf, offset + i * COLLISION_STRIDE_LEN)?);
+    }
+    Ok(collision)
+}
+
+/// Reads a `u64` length followed by that many bytes, returning the byte range.
+fn read_len_prefixed(buf: &[u8], index: usize, end: usize) -> Option<std::ops::Range<usize>> {
+    if end - index < 8 {
+        return None;
+    }
+    let start = index + 8;
+    usize::try_from(read_u64(buf, index))
+        .ok()
+        .and_then(|len| start.checked_add(len))
+        .filter(|stop| *stop <= end)
+        .map(|stop| start..stop)
+}
 
-pub fn load_level(path: &Path, name: &str, spritesheet_handle: ImageHandle) -> Level {
-    let mut background_tiles = HashMap::new();
-    let mut foreground_tiles = HashMap::new();
-    let mut collision_tiles = HashSet::new();
+fn read_entities(
+    buf: &[u8],
+    mut index: usize,
+    end: usize,
+) -> Result<HashMap<Vec2, EntityDef>, LevelLoadError> {
     let mut entities = HashMap::new();
-    let display = path.display();
-    let mut file = match File::open(&path) {
-        Err(why) => {
-            panic!("Couldn't create {}: {}", display, why);
+    let mut has_player_start = false;
+    while index < end {
+        if end - index < ENTITY_HEADER_LEN {
+            return Err(LevelLoadError::TruncatedEntity { offset: index });
+        }
+        let pos = read_position(buf, index)?;
+        let label_range = read_len_prefixed(buf, index + 16, end)
+            .ok_or(LevelLoadError::TruncatedEntity { offset: index })?;
+        let label_start = label_range.start;
+        let label_end = label_range.end;
+        let label = String::from_utf8(buf[label_range].to_vec()).map_err(|source| {
+            LevelLoadError::InvalidEntityLabel {
+                offset: label_start,
+                source,
+            }
+        })?;
+        let def =
+            EntityDef::parse(&label).map_err(|source| LevelLoadError::InvalidEntityProperties {
+                offset: label_start,
+                source,
+            })?;
+        if def.kind() == "player_start" {
+            if has_player_start {
+                return Err(LevelLoadError::DuplicatePlayerStart { offset: index });
+            }
+            has_player_start = true;
+        }
+        if entities.insert(pos, def).is_some() {
+            return Err(LevelLoadError::DuplicateEntity {
+                offset: index,
+                x: pos.x,
+                y: pos.y,
+            });
         }
-        Ok(f) => f,
+        index = label_end;
+    }
+    Ok(entities)
+}
+
+fn read_metadata(
+    buf: &[u8],
+    mut index: usize,
+    end: usize,
+) -> Result<HashMap<String, String>, LevelLoadError> {
+    let mut metadata = HashMap::new();
+    let read_string = |index: usize| -> Result<(String, usize), LevelLoadError> {
+        let range = read_len_prefixed(buf, index, end)
+            .ok_or(LevelLoadError::TruncatedChunk { offset: index })?;
+        let next = range.end;
+        let string = String::from_utf8(buf[range].to_vec()).map_err(|source| {
+            LevelLoadError::InvalidMetadata {
+                offset: index,
+                source,
+            }
+        })?;
+        Ok((string, next))
     };
-    let mut buf: Vec<u8> = Vec::new();
-    file.read_to_end(&mut buf).unwrap();
-    let mut index = 0;
-    let len_bg = u64::from_le_bytes(buf[index..index + 8].try_into().unwrap());
-    index += 8;
-    let len_fg = u64::from_le_bytes(buf[index..index + 8].try_into().unwrap());
-    index += 8;
-    let len_collision = u64::from_le_bytes(buf[index..index + 8].try_into().unwrap());
-    index += 8;
-    let bg_fg_stride_len = (8 + 8 + 2 + 2) as usize;
-    let background_bytes = &buf[index..index + len_bg as usize];
-    for chunk in background_bytes.chunks_exact(bg_fg_stride_len) {
-        let x = i64::from_le_bytes(chunk[0..8].try_into().unwrap());
-        let y = -i64::from_le_bytes(chunk[8..16].try_into().unwrap());
-        let row = i16::from_le_bytes(chunk[16..18].try_into().unwrap());
-        let col = i16::from_le_bytes(chunk[18..20].try_into().unwrap());
-        background_tiles.insert(
-            Vec2::new(x as i32, y as i32),
-            Vec2::new(row as i32, col as i32),
-        );
+    while index < end {
+        let (key, next) = read_string(index)?;
+        let (value, next) = read_string(next)?;
+        metadata.insert(key, value);
+        index = next;
     }
-    index += len_bg as usize;
-    let foreground_bytes = &buf[index..index + len_fg as usize];
-    for chunk in foreground_bytes.chunks_exact(bg_fg_stride_len) {
-        let x = i64::from_le_bytes(chunk[0..8].try_into().unwrap());
-        let y = -i64::from_le_bytes(chunk[8..16].try_into().unwrap());
-        let row = i16::from_le_bytes(chunk[16..18].try_into().unwrap());
-        let col = i16::from_le_bytes(chunk[18..20].try_into().unwrap());
-        foreground_tiles.insert(
-            Vec2::new(x as i32, y as i32),
-            Vec2::new(row as i32, col as i32),
-        );
+    Ok(metadata)
+}
+
+fn parse_v1(buf: &[u8], builder: LevelBuilder) -> Result<LevelBuilder, LevelLoadError> {
+    if buf.len() < HEADER_LEN {
+        return Err(LevelLoadError::TruncatedHeader {
+            expected: HEADER_LEN,
+            len: buf.len(),
+        });
     }
+    let len_bg = read_u64(buf, 0);
+    let len_fg = read_u64(buf, 8);
+    let len_collision = read_u64(buf, 16);
+    let mut index = HEADER_LEN;
+    let background_tiles = read_tiles(buf, "background", index, len_bg)?;
+    index += len_bg as usize;
+    let foreground_tiles = read_tiles(buf, "foreground", index, len_fg)?;
     index += len_fg as usize;
-    let collision_stride_len = (8 + 8) as usize;
-    let collision_bytes = &buf[index..index + len_collision as usize];
-    for chunk in collision_bytes.chunks_exact(collision_stride_len) {
-        let x = i64::from_le_bytes(chunk[0..8].try_into().unwrap());
-        let y = -i64::from_le_bytes(chunk[8..16].try_into().unwrap());
-        collision_tiles.insert(Vec2::new(x as i32, y as i32));
-    }
+    let collision_tiles = read_collision(buf, index, len_collision)?;
     index += len_collision as usize;
-    let len = buf.len();
-    while index < len {
-        let x = i64::from_le_bytes(buf[index..index + 8].try_into().unwrap());
-        index += 8;
-        let y = -i64::from_le_bytes(buf[index..index + 8].try_into().unwrap());
-        index += 8;
-        let label_len = u64::from_le_bytes(buf[index..index + 8].try_into().unwrap());
-        index += 8;
-        let label =
-            String::from_utf8(buf[index..index + label_len as usize].try_into().unwrap()).unwrap();
-        entities.insert(Vec2::new(x as i32, y as i32), label);
-        index += label_len as usize;
-    }
-    let level_builder = Level::new(name, spritesheet_handle)
+    let entities = read_entities(buf, index, buf.len())?;
+    if background_tiles.is_empty() {
+        return Err(LevelLoadError::NoBackgroundTiles);
+    }
+    Ok(builder
         .background_tiles(background_tiles)
         .foreground_tiles(foreground_tiles)
         .collision(collision_tiles)
-        .entities(entities);
-    level_builder.build()
+        .entities(entities))
+}
+
+fn parse_v2(buf: &[u8], mut builder: LevelBuilder) -> Result<LevelBuilder, LevelLoadError> {
+    if buf.len() < V2_HEADER_LEN {
+        return Err(LevelLoadError::TruncatedHeader {
+            expected: V2_HEADER_LEN,
+            len: buf.len(),
+        });
+    }
+    let version = read_u16(buf, 4);
+    if version != FORMAT_VERSION {
+        return Err(LevelLoadError::UnsupportedVersion { version });
+    }
+    let mut seen = HashSet::new();
+    let mut has_background = false;
+    let mut index = V2_HEADER_LEN;
+    while index < buf.len() {
+        if buf.len() - index < CHUNK_HEADER_LEN {
+            return Err(LevelLoadError::TruncatedChunk { offset: index });
+        }
+        let tag: [u8; 4] = buf[index..index + 4].try_into().unwrap();
+        let payload = read_len_prefixed(buf, index + 4, buf.len())
+            .ok_or(LevelLoadError::TruncatedChunk { offset: index })?;
+        if KNOWN_TAGS.contains(&tag) && !seen.insert(tag) {
+            return Err(LevelLoadError::DuplicateChunk { tag, offset: index });
+        }
+        let len = payload.len() as u64;
+        builder = match tag {
+            TAG_BACKGROUND => {
+                let tiles = read_tiles(buf, "background", payload.start, len)?;
+                has_background = !tiles.is_empty();
+                builder.background_tiles(tiles)
+            }
+            TAG_FOREGROUND => {
+                builder.foreground_tiles(read_tiles(buf, "foreground", payload.start, len)?)
+            }
+            TAG_COLLISION => builder.collision(read_collision(buf, payload.start, len)?),
+            TAG_ENTITIES => builder.entities(read_entities(buf, payload.start, payload.end)?),
+            TAG_METADATA => {
+                let metadata = read_metadata(buf, payload.start, payload.end)?;
+                if let Some(value) = metadata.get(TILE_SIZE_KEY) {
+                    if parse_tile_size(value).is_none() {
+                        return Err(LevelLoadError::InvalidTileSize {
+                            offset: payload.start,
+                            value: value.clone(),
+                        });
+                    }
+                }
+                if let Some(value) = metadata.get(CONNECTIVITY_KEY) {
+                    if value.parse::<Connectivity>().is_err() {
+                        return Err(LevelLoadError::InvalidConnectivity {
+                            offset: payload.start,
+                            value: value.clone(),
+                        });
+                    }
+                }
+                builder.metadata(metadata)
+            }
+            _ => builder,
+        };
+        index = payload.end;
+    }
+    if !has_background {
+        return Err(LevelLoadError::NoBackgroundTiles);
+    }
+    Ok(builder)
+}
+
+/// Reads either a v2 or a legacy v1 level file, see the module documentation for the layouts.
+pub fn read_level(path: &Path, name: &str) -> Result<LevelBuilder, LevelLoadError> {
+    let mut buf: Vec<u8> = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut buf))
+        .map_err(|source| LevelLoadError::Io {
+            path: path.to_owned(),
+            source,
+        })?;
+    parse_level(&buf, name)
+}
+
+/// Parses the contents of a v2 or legacy v1 level file.
+pub fn parse_level(buf: &[u8], name: &str) -> Result<LevelBuilder, LevelLoadError> {
+    let builder = Level::new(name);
+    if buf.starts_with(&MAGIC) {
+        parse_v2(buf, builder)
+    } else {
+        parse_v1(buf, builder)
+    }
+}
+
+/// `tile_dim` is used unless the level's metadata sets a tile size.
+pub fn load_level(
+    path: &Path,
+    name: &str,
+    spritesheet_handle: ImageHandle,
+    tile_dim: Vec2,
+) -> Result<Level, LevelLoadError> {
+    Ok(read_level(path, name)?
+        .spritesheet_handle(spritesheet_handle)
+        .tile_dim(tile_dim)
+        .build())
+}
+
+/// The inverse of `read_position`.
+fn write_position(buf: &mut Vec<u8>, pos: Vec2) {
+    buf.extend_from_slice(&(pos.x as i64).to_le_bytes());
+    buf.extend_from_slice(&(-(pos.y as i64)).to_le_bytes());
+}
+
+fn write_len_prefixed(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+/// Records are written in row order so that saving the same level twice gives the same bytes.
+fn sorted(positions: impl Iterator<Item = Vec2>) -> Vec<Vec2> {
+    let mut positions: Vec<Vec2> = positions.collect();
+    positions.sort_unstable_by_key(|pos| (pos.y, pos.x));
+    positions
+}
+
+fn encode_tiles(tiles: &TileGrid<Vec2>) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(tiles.len() * TILE_STRIDE_LEN);
+    for pos in sorted(tiles.keys()) {
+        let tile = tiles.get(pos).unwrap();
+        write_position(&mut buf, pos);
+        buf.extend_from_slice(&(tile.x as i16).to_le_bytes());
+        buf.extend_from_slice(&(tile.y as i16).to_le_bytes());
+    }
+    buf
+}
+
+fn encode_collision(collision: &TileGrid<()>) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(collision.len() * COLLISION_STRIDE_LEN);
+    for pos in sorted(collision.keys()) {
+        write_position(&mut buf, pos);
+    }
+    buf
+}
+
+fn encode_entities(entities: &HashMap<Vec2, EntityDef>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for pos in sorted(entities.keys().copied()) {
+        write_position(&mut buf, pos);
+        write_len_prefixed(&mut buf, entities[&pos].to_string().as_bytes());
+    }
+    buf
+}
+
+fn encode_metadata(metadata: &HashMap<String, String>) -> Vec<u8> {
+    let mut keys: Vec<&String> = metadata.keys().collect();
+    keys.sort_unstable();
+    let mut buf = Vec::new();
+    for key in keys {
+        write_len_prefixed(&mut buf, key.as_bytes());
+        write_len_prefixed(&mut buf, metadata[key].as_bytes());
+    }
+    buf
+}
+
+fn write_chunk(buf: &mut Vec<u8>, tag: [u8; 4], payload: &[u8]) {
+    buf.extend_from_slice(&tag);
+    write_len_prefixed(buf, payload);
+}
+
+/// Encodes a level in the v2 layout read by `load_level`.
+pub fn encode_level(level: &Level) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&MAGIC);
+    buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    write_chunk(
+        &mut buf,
+        TAG_BACKGROUND,
+        &encode_tiles(&level.background_tiles),
+    );
+    write_chunk(
+        &mut buf,
+        TAG_FOREGROUND,
+        &encode_tiles(&level.foreground_tiles),
+    );
+    write_chunk(&mut buf, TAG_COLLISION, &encode_collision(&level.collision));
+    write_chunk(&mut buf, TAG_ENTITIES, &encode_entities(&level.entities));
+    let mut metadata = level.metadata.clone();
+    metadata.insert(TILE_SIZE_KEY.to_owned(), format_tile_size(level.tile_dim));
+    write_chunk(&mut buf, TAG_METADATA, &encode_metadata(&metadata));
+    buf
+}
+
+pub fn save_level(level: &Level, path: &Path) -> std::io::Result<()> {
+    File::create(path)?.write_all(&encode_level(level))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(file_name: &str) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources/maps")
+            .join(file_name);
+        let level = read_level(&path, file_name).unwrap().build();
+        let saved = encode_level(&level);
+        let loaded = parse_level(&saved, file_name).unwrap().build();
+        assert!(loaded == level, "{} changed after saving", file_name);
+        assert_eq!(encode_level(&loaded), saved);
+    }
+
+    #[test]
+    fn collision_test_round_trips() {
+        round_trip("collision_test.lvl");
+    }
+
+    #[test]
+    fn level_1_round_trips() {
+        round_trip("level_1.lvl");
+    }
+
+    fn tile(x: i64, y: i64) -> Vec<u8> {
+        let mut buf = [x.to_le_bytes(), y.to_le_bytes()].concat();
+        buf.extend_from_slice(&[0; 4]);
+        buf
+    }
+
+    fn entity(x: i64, y: i64, label: &[u8]) -> Vec<u8> {
+        let mut buf = [x.to_le_bytes(), y.to_le_bytes()].concat();
+        write_len_prefixed(&mut buf, label);
+        buf
+    }
+
+    fn v1_file(background: &[u8], foreground: &[u8], collision: &[u8], entities: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for section in [background, foreground, collision] {
+            buf.extend_from_slice(&(section.len() as u64).to_le_bytes());
+        }
+        [
+            buf,
+            background.to_vec(),
+            foreground.to_vec(),
+            collision.to_vec(),
+            entities.to_vec(),
+        ]
+        .concat()
+    }
+
+    fn parse_error(buf: &[u8]) -> LevelLoadError {
+        match parse_level(buf, "test") {
+            Ok(_) => panic!("loaded an invalid level"),
+            Err(why) => why,
+        }
+    }
+
+    #[test]
+    fn loads_a_minimal_v1_file() {
+        let buf = v1_file(&tile(2, -3), &[], &[0; 16], &entity(1, 1, b"player_start"));
+        let level = parse_level(&buf, "test").unwrap().build();
+        assert!(level.background_tiles.get(Vec2::new(2, 3)) == Some(&Vec2::new(0, 0)));
+        assert!(level.entity(Vec2::new(1, -1)).is_some());
+    }
+
+    #[test]
+    fn rejects_a_truncated_header() {
+        let error = parse_error(&[0; 10]);
+        assert!(matches!(
+            error,
+            LevelLoadError::TruncatedHeader {
+                expected: HEADER_LEN,
+                len: 10
+            }
+        ));
+    }
+
+    #[test]
+    fn rejects_a_section_longer_than_the_file() {
+        let mut buf = v1_file(&tile(0, 0), &[], &[], &[]);
+        buf[0] = 40;
+        let error = parse_error(&buf);
+        assert!(matches!(
+            error,
+            LevelLoadError::SectionOutOfBounds {
+                section: "background",
+                offset: HEADER_LEN,
+                len: 40,
+                file_len: 44,
+            }
+        ));
+    }
+
+    #[test]
+    fn rejects_a_section_that_isnt_whole_records() {
+        let buf = v1_file(&tile(0, 0), &[], &[0; 12], &[]);
+        let error = parse_error(&buf);
+        assert!(matches!(
+            error,
+            LevelLoadError::BadSectionStride {
+                section: "collision",
+                len: 12,
+                stride: COLLISION_STRIDE_LEN,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn rejects_coordinates_outside_i32() {
+        let buf = v1_file(&tile(0, 0), &tile(i64::from(i32::MAX) + 1, 0), &[], &[]);
+        let error = parse_error(&buf);
+        assert!(matches!(
+            error,
+            LevelLoadError::CoordinateOutOfRange { offset, value }
+                if offset == HEADER_LEN + TILE_STRIDE_LEN && value == i64::from(i32::MAX) + 1
+        ));
+        // Y is negated on load, so `i32::MIN` doesn't fit either.
+        let buf = v1_file(&tile(0, i64::from(i32::MIN)), &[], &[], &[]);
+        let error = parse_error(&buf);
+        assert!(matches!(
+            error,
+            LevelLoadError::CoordinateOutOfRange { offset, .. } if offset == HEADER_LEN + 8
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_entities() {
+        let entities_start = HEADER_LEN + TILE_STRIDE_LEN;
+        let buf = v1_file(&tile(0, 0), &[], &[], &[0; 20]);
+        let error = parse_error(&buf);
+        assert!(matches!(
+            error,
+            LevelLoadError::TruncatedEntity { offset } if offset == entities_start
+        ));
+        // The label runs past the end of the file.
+        let mut entities = entity(0, 0, b"door");
+        entities.truncate(entities.len() - 1);
+        let error = parse_error(&v1_file(&tile(0, 0), &[], &[], &entities));
+        assert!(matches!(
+            error,
+            LevelLoadError::TruncatedEntity { offset } if offset == entities_start
+        ));
+    }
+
+    #[test]
+    fn rejects_labels_that_arent_utf8() {
+        let buf = v1_file(&tile(0, 0), &[], &[], &entity(0, 0, &[0xff, 0xfe]));
+        let error = parse_error(&buf);
+        assert!(matches!(
+            error,
+            LevelLoadError::InvalidEntityLabel { offset, .. }
+                if offset == HEADER_LEN + TILE_STRIDE_LEN + ENTITY_HEADER_LEN
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_entity_properties() {
+        let buf = v1_file(
+            &tile(0, 0),
+            &[],
+            &[],
+            &entity(0, 0, b"enemy_smart speed=fast"),
+        );
+        let error = parse_error(&buf);
+        assert!(matches!(
+            error,
+            LevelLoadError::InvalidEntityProperties { .. }
+        ));
+    }
+
+    #[test]
+    fn rejects_two_entities_on_one_tile() {
+        let first = entity(3, -2, b"enemy_dumb patrol=gate");
+        let entities = [first.clone(), entity(3, -2, b"patrol:gate:0")].concat();
+        let buf = v1_file(&tile(0, 0), &[], &[], &entities);
+        let error = parse_error(&buf);
+        assert!(matches!(
+            error,
+            LevelLoadError::DuplicateEntity { offset, x: 3, y: 2 }
+                if offset == HEADER_LEN + TILE_STRIDE_LEN + first.len()
+        ));
+    }
+
+    #[test]
+    fn rejects_a_level_without_background() {
+        let error = parse_error(&v1_file(&[], &[], &[], &[]));
+        assert!(matches!(error, LevelLoadError::NoBackgroundTiles));
+    }
+
+    fn v2_file(chunks: &[([u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        for (tag, payload) in chunks {
+            write_chunk(&mut buf, *tag, payload);
+        }
+        buf
+    }
+
+    #[test]
+    fn skips_unknown_chunks() {
+        let buf = v2_file(&[
+            (*b"XTRA", vec![1, 2, 3]),
+            (TAG_BACKGROUND, tile(0, 0)),
+            (*b"XTRA", Vec::new()),
+            (TAG_ENTITIES, entity(2, 0, b"player_start")),
+        ]);
+        let level = parse_level(&buf, "test").unwrap().build();
+        assert_eq!(level.background_tiles.len(), 1);
+        assert!(level.entity(Vec2::new(2, 0)).is_some());
+    }
+
+    #[test]
+    fn rejects_a_repeated_known_chunk() {
+        let buf = v2_file(&[(TAG_BACKGROUND, tile(0, 0)), (TAG_BACKGROUND, tile(1, 0))]);
+        let error = parse_error(&buf);
+        let second = V2_HEADER_LEN + CHUNK_HEADER_LEN + TILE_STRIDE_LEN;
+        assert!(matches!(
+            error,
+            LevelLoadError::DuplicateChunk { tag: TAG_BACKGROUND, offset } if offset == second
+        ));
+    }
+
+    #[test]
+    fn rejects_a_truncated_v2_header() {
+        let error = parse_error(&[&MAGIC[..], &[2]].concat());
+        assert!(matches!(
+            error,
+            LevelLoadError::TruncatedHeader {
+                expected: V2_HEADER_LEN,
+                len: 5
+            }
+        ));
+        assert_eq!(
+            error.to_string(),
+            "truncated header: expected 6 bytes, file is 5 bytes"
+        );
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut buf = v2_file(&[(TAG_BACKGROUND, tile(0, 0))]);
+        buf[4] = 3;
+        let error = parse_error(&buf);
+        assert!(matches!(
+            error,
+            LevelLoadError::UnsupportedVersion { version: 3 }
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_chunks() {
+        let buf = v2_file(&[(TAG_BACKGROUND, tile(0, 0))]);
+        // The payload is cut short.
+        let error = parse_error(&buf[..buf.len() - 1]);
+        assert!(matches!(
+            error,
+            LevelLoadError::TruncatedChunk {
+                offset: V2_HEADER_LEN
+            }
+        ));
+        // The chunk header is cut short.
+        let error = parse_error(&buf[..V2_HEADER_LEN + 6]);
+        assert!(matches!(
+            error,
+            LevelLoadError::TruncatedChunk {
+                offset: V2_HEADER_LEN
+            }
+        ));
+    }
 }
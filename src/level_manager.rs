@@ -0,0 +1,89 @@
+use std::path::PathBuf;
+
+use bevy_ecs::prelude::*;
+use serde::Deserialize;
+
+use crate::components::*;
+use crate::resources::Level;
+
+#[derive(Deserialize)]
+pub struct LevelEntry {
+    /// Referred to by the `target` of doors.
+    pub id: String,
+    pub name: String,
+    pub map: PathBuf,
+    pub spritesheet: PathBuf,
+    /// Width and height in pixels.
+    pub tile_size: (u32, u32),
+    #[serde(default)]
+    pub music: Option<PathBuf>,
+}
+
+pub struct LevelTransition {
+    pub level: usize,
+    /// The spawn point to place the player at, `player_start` when `None`.
+    pub spawn: Option<String>,
+}
+
+pub struct LevelManager {
+    pub levels: Vec<LevelEntry>,
+    pub current: usize,
+    /// Set by gameplay systems, performed between frames since loading needs the `Engine`.
+    pub pending: Option<LevelTransition>,
+}
+
+impl LevelManager {
+    pub fn new(levels: Vec<LevelEntry>) -> Self {
+        Self {
+            levels,
+            current: 0,
+            pending: None,
+        }
+    }
+    pub fn current(&self) -> &LevelEntry {
+        &self.levels[self.current]
+    }
+    pub fn index_of(&self, id: &str) -> Option<usize> {
+        self.levels.iter().position(|entry| entry.id == id)
+    }
+    /// The level after the current one, wrapping back to the first.
+    pub fn next(&self) -> usize {
+        (self.current + 1) % self.levels.len()
+    }
+    pub fn request(&mut self, level: usize, spawn: Option<String>) {
+        if self.pending.is_none() {
+            self.pending = Some(LevelTransition { level, spawn });
+        }
+    }
+}
+
+/// Despawns everything spawned from the current level and removes it.
+pub fn unload_level(world: &mut World) {
+    let entities: Vec<Entity> = world
+        .query_filtered::<Entity, With<LevelEntity>>()
+        .iter(world)
+        .collect();
+    for entity in entities {
+        world.despawn(entity);
+    }
+    world.remove_resource::<Level>();
+}
+
+/// Moves the player to the named spawn point, returning false if the level has none.
+pub fn place_player(world: &mut World, spawn: &str) -> bool {
+    let spawn_pos = world
+        .query::<(&SpawnPoint, &Position)>()
+        .iter(world)
+        .find(|(spawn_point, _)| spawn_point.name == spawn)
+        .map(|(_, pos)| pos.0);
+    if let Some(spawn_pos) = spawn_pos {
+        let player = world.query_filtered::<Entity, With<Player>>().single(world);
+        world
+            .entity_mut(player)
+            .insert(Position(spawn_pos))
+            .insert(Velocity::default());
+        true
+    } else {
+        false
+    }
+}
@@ -0,0 +1,316 @@
+//! A RON representation of a `Level`, meant to be read and diffed by people.
+//!
+//! Positions use game coordinates, with Y pointing down, unlike the binary format. Tiles are
+//! written as `(x, y, spritesheet_x, spritesheet_y)`, collision as `(x, y)` and entities as
+//! `(x, y, label)`, one per line and sorted by position.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use engine::types::Vec2;
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::entity_def::{EntityDef, EntityDefError};
+use crate::pathfinding::{Connectivity, CONNECTIVITY_KEY};
+use crate::resources::{format_tile_size, parse_tile_size, Level, LevelBuilder, TILE_SIZE_KEY};
+use crate::tile_grid::TileGrid;
+
+#[derive(Serialize, Deserialize)]
+pub struct LevelText {
+    pub name: String,
+    pub background: Vec<(i32, i32, i16, i16)>,
+    #[serde(default)]
+    pub foreground: Vec<(i32, i32, i16, i16)>,
+    #[serde(default)]
+    pub collision: Vec<(i32, i32)>,
+    #[serde(default)]
+    pub entities: Vec<(i32, i32, String)>,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[derive(Debug)]
+pub enum LevelTextError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        source: ron::error::SpannedError,
+    },
+    Serialize(ron::Error),
+    InvalidEntityProperties {
+        x: i32,
+        y: i32,
+        source: EntityDefError,
+    },
+    DuplicatePlayerStart {
+        x: i32,
+        y: i32,
+    },
+    DuplicateEntity {
+        x: i32,
+        y: i32,
+    },
+    NoBackgroundTiles,
+    InvalidTileSize(String),
+    InvalidConnectivity(String),
+    SpritesheetCoordinateOutOfRange {
+        x: i32,
+        y: i32,
+    },
+}
+
+impl fmt::Display for LevelTextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, source } => {
+                write!(f, "couldn't access {}: {}", path.display(), source)
+            }
+            Self::Parse { path, source } => write!(f, "{}:{}", path.display(), source),
+            Self::Serialize(source) => write!(f, "couldn't serialize level: {}", source),
+            Self::InvalidEntityProperties { x, y, source } => {
+                write!(f, "invalid entity label at ({}, {}): {}", x, y, source)
+            }
+            Self::DuplicatePlayerStart { x, y } => {
+                write!(f, "second player_start entity at ({}, {})", x, y)
+            }
+            Self::DuplicateEntity { x, y } => write!(f, "second entity at ({}, {})", x, y),
+            Self::NoBackgroundTiles => write!(f, "level has no background tiles"),
+            Self::InvalidTileSize(value) => {
+                write!(f, "invalid tile size \"{}\" in metadata", value)
+            }
+            Self::InvalidConnectivity(value) => write!(
+                f,
+                "invalid connectivity \"{}\" in metadata, expected 4 or 8",
+                value
+            ),
+            Self::SpritesheetCoordinateOutOfRange { x, y } => write!(
+                f,
+                "spritesheet coordinates of the tile at ({}, {}) do not fit in i16",
+                x, y
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LevelTextError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            Self::Parse { source, .. } => Some(source),
+            Self::Serialize(source) => Some(source),
+            Self::InvalidEntityProperties { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+fn sorted_tiles(tiles: &TileGrid<Vec2>) -> Result<Vec<(i32, i32, i16, i16)>, LevelTextError> {
+    let mut result = Vec::with_capacity(tiles.len());
+    for (pos, tile) in tiles.iter() {
+        let out_of_range =
+            || LevelTextError::SpritesheetCoordinateOutOfRange { x: pos.x, y: pos.y };
+        let sheet_x = i16::try_from(tile.x).map_err(|_| out_of_range())?;
+        let sheet_y = i16::try_from(tile.y).map_err(|_| out_of_range())?;
+        result.push((pos.x, pos.y, sheet_x, sheet_y));
+    }
+    result.sort_unstable_by_key(|(x, y, _, _)| (*y, *x));
+    Ok(result)
+}
+
+impl LevelText {
+    pub fn from_level(level: &Level) -> Result<Self, LevelTextError> {
+        let mut collision: Vec<(i32, i32)> =
+            level.collision.keys().map(|pos| (pos.x, pos.y)).collect();
+        collision.sort_unstable_by_key(|(x, y)| (*y, *x));
+        let mut entities: Vec<(i32, i32, String)> = level
+            .entities
+            .iter()
+            .map(|(pos, def)| (pos.x, pos.y, def.to_string()))
+            .collect();
+        entities.sort_unstable_by_key(|(x, y, _)| (*y, *x));
+        let mut metadata: BTreeMap<String, String> = level
+            .metadata
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        metadata.insert(TILE_SIZE_KEY.to_owned(), format_tile_size(level.tile_dim));
+        Ok(Self {
+            name: level.name.clone(),
+            background: sorted_tiles(&level.background_tiles)?,
+            foreground: sorted_tiles(&level.foreground_tiles)?,
+            collision,
+            entities,
+            metadata,
+        })
+    }
+    pub fn into_builder(self) -> Result<LevelBuilder, LevelTextError> {
+        if self.background.is_empty() {
+            return Err(LevelTextError::NoBackgroundTiles);
+        }
+        if let Some(value) = self.metadata.get(TILE_SIZE_KEY) {
+            if parse_tile_size(value).is_none() {
+                return Err(LevelTextError::InvalidTileSize(value.clone()));
+            }
+        }
+        if let Some(value) = self.metadata.get(CONNECTIVITY_KEY) {
+            if value.parse::<Connectivity>().is_err() {
+                return Err(LevelTextError::InvalidConnectivity(value.clone()));
+            }
+        }
+        let tiles = |tiles: Vec<(i32, i32, i16, i16)>| -> HashMap<Vec2, Vec2> {
+            tiles
+                .into_iter()
+                .map(|(x, y, sheet_x, sheet_y)| {
+                    (Vec2::new(x, y), Vec2::new(sheet_x as i32, sheet_y as i32))
+                })
+                .collect()
+        };
+        let collision: HashSet<Vec2> = self
+            .collision
+            .into_iter()
+            .map(|(x, y)| Vec2::new(x, y))
+            .collect();
+        let mut entities = HashMap::with_capacity(self.entities.len());
+        let mut has_player_start = false;
+        for (x, y, label) in self.entities {
+            let def = EntityDef::parse(&label)
+                .map_err(|source| LevelTextError::InvalidEntityProperties { x, y, source })?;
+            if def.kind() == "player_start" {
+                if has_player_start {
+                    return Err(LevelTextError::DuplicatePlayerStart { x, y });
+                }
+                has_player_start = true;
+            }
+            if entities.insert(Vec2::new(x, y), def).is_some() {
+                return Err(LevelTextError::DuplicateEntity { x, y });
+            }
+        }
+        Ok(Level::new(&self.name)
+            .background_tiles(tiles(self.background))
+            .foreground_tiles(tiles(self.foreground))
+            .collision(collision)
+            .entities(entities)
+            .metadata(self.metadata.into_iter().collect()))
+    }
+}
+
+pub fn read_level_text(path: &Path) -> Result<LevelBuilder, LevelTextError> {
+    let text = fs::read_to_string(path).map_err(|source| LevelTextError::Io {
+        path: path.to_owned(),
+        source,
+    })?;
+    let level_text: LevelText = ron::from_str(&text).map_err(|source| LevelTextError::Parse {
+        path: path.to_owned(),
+        source,
+    })?;
+    level_text.into_builder()
+}
+
+pub fn save_level_text(level: &Level, path: &Path) -> Result<(), LevelTextError> {
+    let config = PrettyConfig::new().struct_names(true);
+    let text = ron::ser::to_string_pretty(&LevelText::from_level(level)?, config)
+        .map_err(LevelTextError::Serialize)?;
+    fs::write(path, text + "\n").map_err(|source| LevelTextError::Io {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::file::{encode_level, parse_level, read_level, MAGIC};
+
+    fn level_text(entities: &[(i32, i32, &str)]) -> LevelText {
+        LevelText {
+            name: "test".to_owned(),
+            background: vec![(0, 0, 0, 0), (1, 0, 0, 0)],
+            foreground: Vec::new(),
+            collision: Vec::new(),
+            entities: entities
+                .iter()
+                .map(|(x, y, label)| (*x, *y, (*label).to_owned()))
+                .collect(),
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn single_player_start_is_accepted() {
+        assert!(level_text(&[(0, 0, "player_start"), (1, 0, "enemy_dumb")])
+            .into_builder()
+            .is_ok());
+    }
+
+    #[test]
+    fn duplicate_player_start_is_rejected() {
+        match level_text(&[(0, 0, "player_start"), (1, 0, "player_start")]).into_builder() {
+            Err(LevelTextError::DuplicatePlayerStart { x: 1, y: 0 }) => {}
+            Err(why) => panic!("unexpected error: {}", why),
+            Ok(_) => panic!("a level with two player_start entities was accepted"),
+        }
+    }
+
+    #[test]
+    fn two_entities_on_one_tile_are_rejected() {
+        match level_text(&[(1, 0, "enemy_dumb patrol=gate"), (1, 0, "patrol:gate:0")])
+            .into_builder()
+        {
+            Err(LevelTextError::DuplicateEntity { x: 1, y: 0 }) => {}
+            Err(why) => panic!("unexpected error: {}", why),
+            Ok(_) => panic!("a level with two entities on one tile was accepted"),
+        }
+    }
+
+    fn shipped_map(file_name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources/maps")
+            .join(file_name)
+    }
+
+    /// What `lvl2txt` and then `txt2lvl` do to a map.
+    fn binary_text_binary(file_name: &str) {
+        let path = shipped_map(file_name);
+        assert!(!fs::read(&path).unwrap().starts_with(&MAGIC));
+        let level = read_level(&path, file_name).unwrap().build();
+        let text = LevelText::from_level(&level).unwrap().into_builder();
+        assert!(
+            text.unwrap().build() == level,
+            "{} changed as text",
+            file_name
+        );
+        let text_path =
+            env::temp_dir().join(format!("rs-game-{}-{}.ron", std::process::id(), file_name));
+        save_level_text(&level, &text_path).unwrap();
+        let from_text = read_level_text(&text_path);
+        fs::remove_file(&text_path).unwrap();
+        let from_text = from_text.unwrap().build();
+        assert!(from_text == level, "{} changed in a text file", file_name);
+        let v2 = parse_level(&encode_level(&from_text), file_name)
+            .unwrap()
+            .build();
+        assert!(
+            v2 == level,
+            "{} changed going from v1 to text to v2",
+            file_name
+        );
+    }
+
+    #[test]
+    fn collision_test_converts_both_ways() {
+        binary_text_binary("collision_test.lvl");
+    }
+
+    #[test]
+    fn level_1_converts_both_ways() {
+        binary_text_binary("level_1.lvl");
+    }
+}
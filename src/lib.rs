@@ -0,0 +1,32 @@
+pub mod ai;
+pub mod collision;
+pub mod components;
+pub mod determinism;
+pub mod entity_def;
+pub mod file;
+pub mod level_manager;
+pub mod level_text;
+pub mod manifest;
+pub mod menu;
+pub mod pathfinding;
+pub mod raycast;
+pub mod render;
+pub mod resources;
+pub mod schedule;
+pub mod spawn;
+pub mod steering;
+pub mod systems;
+pub mod tile_grid;
+pub mod trigger;
+pub mod util;
+
+pub const SCREEN_WIDTH: u32 = 320;
+pub const SCREEN_HEIGHT: u32 = 240;
+
+pub enum GameRunMode {
+    MainMenu,
+    Game,
+    Error,
+}
+
+pub type ShouldQuit = bool;
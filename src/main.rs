@@ -1,43 +1,43 @@
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use bevy_ecs::prelude::*;
+use engine::resource::ImageHandle;
 use engine::types::{Color, FontSettings, Vec2, Vec2F, VirtualKeyCode};
 use engine::{run, Context, Engine, GameState};
 
-mod components;
-use components::*;
-mod resources;
-use resources::*;
-mod systems;
-use systems::*;
-mod render;
-use render::*;
-mod file;
-use file::*;
-mod menu;
-use menu::*;
-mod util;
+use rs_game::ai::*;
+use rs_game::components::*;
+use rs_game::determinism::*;
+use rs_game::file::*;
+use rs_game::level_manager::*;
+use rs_game::manifest::*;
+use rs_game::menu::*;
+use rs_game::render::*;
+use rs_game::resources::*;
+use rs_game::schedule::*;
+use rs_game::spawn::*;
+use rs_game::systems::*;
+use rs_game::util::*;
+use rs_game::{GameRunMode, ShouldQuit, SCREEN_HEIGHT, SCREEN_WIDTH};
 
-const SCREEN_WIDTH: u32 = 320;
-const SCREEN_HEIGHT: u32 = 240;
 const SCREEN_DIM: Vec2 = Vec2::new(SCREEN_WIDTH as i32, SCREEN_HEIGHT as i32);
 
-const TILE_WIDTH: u32 = 32;
-const TILE_HEIGHT: u32 = 32;
-const TILE_DIM: Vec2 = Vec2::new(TILE_WIDTH as i32, TILE_HEIGHT as i32);
+const ERROR_LINE_CHARS: usize = 24;
 
-pub enum GameRunMode {
-    MainMenu,
-    Game,
-}
-
-pub type ShouldQuit = bool;
+const MANIFEST_PATH: &str = "resources/manifest.ron";
+/// Used to report errors in the manifest, which is where the font is normally listed.
+const FALLBACK_FONT: &str = "resources/fonts/JetbrainsMonoRegular.ttf";
 
 struct Game {
     ctx: Context,
     world: World,
+    /// Run once a frame.
     schedule: Schedule,
+    /// Runs the game simulation, once a tick of the `FixedTimestep`.
+    fixed_schedule: Schedule,
+    spritesheets: HashMap<PathBuf, ImageHandle>,
 }
 
 impl Game {
@@ -49,11 +49,6 @@ impl Game {
         };
         let mut world = World::new();
         world.spawn().insert_bundle(CameraBundle::default());
-        world.spawn().insert_bundle(PlayerBundle {
-            position: Position::new(1.0, 1.0),
-            speed: Speed::new(7.0),
-            ..Default::default()
-        });
         /*
         for i in 5..105 {
             world.spawn().insert_bundle(EnemyBundle {
@@ -62,13 +57,11 @@ impl Game {
             });
         }
         */
-        world.spawn().insert_bundle(SmartEnemyBundle {
-            enemy: EnemyBundle {
-                position: Position::new(5.0, 5.0),
-                ..Default::default()
-            },
-            ..Default::default()
-        });
+        world.insert_resource(EntityRegistry::default());
+        world.insert_resource(Behaviors::default());
+        insert_simulation_resources(&mut world);
+        world.insert_resource(FixedTimestep::default());
+        world.insert_resource(Rng::from_clock());
         world.insert_resource(ControlBindings::default());
         let controls = world.get_resource::<ControlBindings>().unwrap();
         let mut movement_bindings: Vec<VirtualKeyCode> = Vec::with_capacity(8); // magic number, expects 2 per control,
@@ -82,7 +75,7 @@ impl Game {
         world.insert_resource(movement_bindings);
         world.insert_resource(Screen { dim: SCREEN_DIM });
         world.insert_resource(TileMeta {
-            dim: TILE_DIM,
+            dim: DEFAULT_TILE_DIM,
             visible: Vec2::new(0, 0),
             offset: Vec2F::new(0.0, 0.0),
         });
@@ -98,50 +91,117 @@ impl Game {
                 .with_system(handle_quit_button),
         );
         schedule.add_stage(
-            "update_game",
+            "update_error",
             SystemStage::parallel()
-                .with_run_criteria(is_in_game)
-                .with_system(update_path_timers)
-                .with_system(handle_player_movement)
-                .with_system(update_player_trunc_pos)
-                .with_system(propagate_pathfinding_wave.after(update_player_trunc_pos))
-                .with_system(build_enemy_bfs_paths.after(propagate_pathfinding_wave))
-                .with_system(handle_enemy_path_movement.after(build_enemy_bfs_paths))
-                .with_system(handle_enemy_movement_dumb)
-                .with_system(handle_collision)
-                .with_system(handle_player_camera)
-                .with_system(get_visible_tiles)
-                .with_system(get_camera_offset.before(get_tile_offset))
-                .with_system(get_tile_offset),
+                .with_run_criteria(is_in_error_screen)
+                .with_system(handle_quit_button),
         );
-
         Self {
             ctx,
             world,
             schedule,
+            fixed_schedule: fixed_schedule(false),
+            spritesheets: HashMap::new(),
         }
     }
-    fn game_create(&mut self, engine: &mut Engine) {
-        let image_handle = engine
-            .resource_manager
-            .load_image(Path::new("resources/images/level_1_spritesheet.png"));
-        self.world.insert_resource(load_level(
-            Path::new("resources/maps/collision_test.lvl"),
-            //Path::new("resources/maps/level_1.lvl"),
-            "Level 1",
-            image_handle,
-        ));
+    fn game_create(&mut self, engine: &mut Engine, manifest: Manifest) {
+        self.world
+            .insert_resource(FixedTimestep::new(manifest.tick_rate));
+        match manifest.seed {
+            Some(seed) => {
+                self.world.insert_resource(Rng::new(seed));
+                self.fixed_schedule = fixed_schedule(true);
+            }
+            None => self.world.insert_resource(Rng::from_clock()),
+        }
+        self.world
+            .insert_resource(Behaviors::new(manifest.behaviors));
+        self.world
+            .insert_resource(LevelManager::new(manifest.levels));
+        self.change_level(
+            engine,
+            LevelTransition {
+                level: 0,
+                spawn: None,
+            },
+        );
+    }
+    fn change_level(&mut self, engine: &mut Engine, transition: LevelTransition) {
+        unload_level(&mut self.world);
+        let mut level_manager = self.world.resource_mut::<LevelManager>();
+        level_manager.current = transition.level;
+        let entry = level_manager.current();
+        let map = entry.map.clone();
+        let name = entry.name.clone();
+        let spritesheet = entry.spritesheet.clone();
+        let tile_dim = Vec2::new(entry.tile_size.0 as i32, entry.tile_size.1 as i32);
+        let image_handle = *self
+            .spritesheets
+            .entry(spritesheet)
+            .or_insert_with_key(|path| engine.resource_manager.load_image(path));
+        match load_level(&map, &name, image_handle, tile_dim) {
+            Ok(level) => {
+                self.world.resource_mut::<TileMeta>().dim = level.tile_dim;
+                self.world.insert_resource(level);
+            }
+            Err(why) => {
+                eprintln!("Couldn't load level: {}", why);
+                self.error_screen_create(engine, &format!("Couldn't load level: {}", why));
+                return;
+            }
+        }
+        for unknown in spawn_level_entities(&mut self.world) {
+            eprintln!("Skipping {}", unknown);
+        }
+        let level_manager = self.world.resource::<LevelManager>();
+        for (pos, door) in self.world.resource::<Level>().entities_of_kind("door") {
+            if let Some(target) = door.target_level() {
+                if level_manager.index_of(target).is_none() {
+                    eprintln!(
+                        "Door at ({}, {}) leads to unknown level \"{}\"",
+                        pos.x, pos.y, target
+                    );
+                }
+            }
+        }
+        if self
+            .world
+            .query::<&Player>()
+            .iter(&self.world)
+            .next()
+            .is_none()
+        {
+            eprintln!("Couldn't load level: no player_start entity");
+            self.error_screen_create(engine, "Couldn't load level: no player_start entity");
+            return;
+        }
+        if let Some(spawn) = transition.spawn {
+            if !place_player(&mut self.world, &spawn) {
+                eprintln!(
+                    "No spawn point \"{}\" in {}, using player_start",
+                    spawn, name
+                );
+            }
+        }
+        reset_interpolation(&mut self.world);
     }
     fn game_update(&mut self, engine: &mut Engine) {
+        // Bodies and the camera are drawn between where they were at the last two ticks.
+        let alpha = self.world.resource::<FixedTimestep>().alpha();
         let cam = self.world.query::<&Camera>().single(&self.world);
-        let cam_offset = cam.offset;
-        let (_, player_pos) = self
+        let cam_offset = lerp(cam.previous_offset, cam.offset, alpha);
+        let (_, player_pos, player_previous_pos, player_collider) = self
             .world
-            .query::<(&Player, &Position)>()
+            .query::<(&Player, &Position, Option<&PreviousPosition>, &Collider)>()
             .single(&self.world);
-        let player_pos = player_pos.as_wrapped();
+        let player_pos = match player_previous_pos {
+            Some(previous_pos) => lerp(previous_pos.0, player_pos.0, alpha),
+            None => player_pos.as_wrapped(),
+        };
+        let player_collider = *player_collider;
         let level = self.world.resource::<Level>();
         let tile_meta = self.world.resource::<TileMeta>();
+        let tile_dim = tile_meta.dim;
         {
             let screen = &mut engine.screen;
             screen.clear(Color::new(50, 50, 193, 255));
@@ -149,32 +209,33 @@ impl Game {
         render_tiles(
             tile_meta.visible,
             cam_offset,
-            tile_meta.offset,
+            tile_offset(cam_offset, tile_dim),
             level,
-            TILE_DIM,
+            tile_dim,
             engine,
         );
         let screen = &mut engine.screen;
-        let mut enemy_query = self.world.query::<(&Enemy, &Position)>();
-        for (_enemy, pos) in enemy_query.iter(&self.world) {
-            render_enemy(pos.0, cam_offset, TILE_DIM, screen);
+        let mut enemy_query = self
+            .world
+            .query::<(&Enemy, &Position, Option<&PreviousPosition>, &Collider)>();
+        for (_enemy, pos, previous_pos, collider) in enemy_query.iter(&self.world) {
+            let pos = previous_pos.map_or(pos.0, |previous_pos| lerp(previous_pos.0, pos.0, alpha));
+            render_enemy(pos, collider, cam_offset, tile_dim, screen);
         }
-        render_player(player_pos, cam_offset, TILE_DIM, screen);
+        render_player(player_pos, &player_collider, cam_offset, tile_dim, screen);
     }
-    fn main_menu_create(&mut self, engine: &mut Engine) {
+    fn main_menu_create(&mut self, engine: &mut Engine, font: &Path) {
         let settings = FontSettings {
             scale: 10.0,
             ..FontSettings::default()
         };
-        let font_handle = engine.resource_manager.load_font(
-            Path::new("resources/fonts/JetbrainsMonoRegular.ttf"),
-            settings,
-        );
+        let font_handle = engine.resource_manager.load_font(font, settings);
         let (button_1_handle, button_1_bounds) =
             create_centered_button(engine, font_handle, "Press Space to Toggle", 20);
         let (button_quit_handle, button_quit_bounds) =
             create_centered_button(engine, font_handle, "Quit", 70);
         self.world.insert_resource(MainMenuResources {
+            font_handle,
             button_1_handle,
             button_1_bounds,
             button_quit_handle,
@@ -189,12 +250,40 @@ impl Game {
         let mmr = self.world.resource::<MainMenuResources>();
         render_main_menu(mmr, engine);
     }
+    fn error_screen_create(&mut self, engine: &mut Engine, message: &str) {
+        let font_handle = self.world.resource::<MainMenuResources>().font_handle;
+        let lines = wrap_text(message, ERROR_LINE_CHARS)
+            .iter()
+            .enumerate()
+            .map(|(i, line)| create_centered_text(engine, font_handle, line, 110 + i as i32 * 20))
+            .collect();
+        self.world.insert_resource(ErrorScreenResources { lines });
+        self.world.insert_resource(GameRunMode::Error);
+    }
+    fn error_screen_update(&mut self, engine: &mut Engine) {
+        {
+            let screen = &mut engine.screen;
+            screen.clear(Color::new(120, 20, 20, 255));
+        }
+        let esr = self.world.resource::<ErrorScreenResources>();
+        let mmr = self.world.resource::<MainMenuResources>();
+        render_error_screen(esr, mmr, engine);
+    }
 }
 
 impl GameState for Game {
     fn on_create(&mut self, engine: &mut Engine) -> bool {
-        self.game_create(engine);
-        self.main_menu_create(engine);
+        match load_manifest(Path::new(MANIFEST_PATH)) {
+            Ok(manifest) => {
+                self.main_menu_create(engine, &manifest.font);
+                self.game_create(engine, manifest);
+            }
+            Err(why) => {
+                eprintln!("Couldn't load manifest: {}", why);
+                self.main_menu_create(engine, Path::new(FALLBACK_FONT));
+                self.error_screen_create(engine, &format!("Couldn't load manifest: {}", why));
+            }
+        }
 
         true
     }
@@ -202,13 +291,35 @@ impl GameState for Game {
         engine
             .window
             .set_title(&format!("{}ms", elapsed_time.as_millis()));
-        self.world.insert_resource(elapsed_time);
+        // Input is only sampled once a frame, so every tick run for the frame sees the same keys.
+        // The fixed schedule must only look at keys being held: a `key_pressed` there would fire
+        // on each of those ticks, or on none of them when the frame runs no tick.
         self.world.insert_resource(engine.input.clone());
         self.schedule.run(&mut self.world);
+        let mut timestep = self.world.resource_mut::<FixedTimestep>();
+        let ticks = timestep.advance(elapsed_time);
+        let tick = timestep.tick;
+        // Systems move things by the time passed, which is always a whole tick.
+        self.world.insert_resource(tick);
+        let mut transition = None;
+        for _ in 0..ticks {
+            self.fixed_schedule.run(&mut self.world);
+            transition = self
+                .world
+                .get_resource_mut::<LevelManager>()
+                .and_then(|mut level_manager| level_manager.pending.take());
+            if transition.is_some() {
+                break;
+            }
+        }
+        if let Some(transition) = transition {
+            self.change_level(engine, transition);
+        }
         let state = self.world.resource::<GameRunMode>();
         match state {
             GameRunMode::Game => self.game_update(engine),
             GameRunMode::MainMenu => self.main_menu_update(engine),
+            GameRunMode::Error => self.error_screen_update(engine),
         };
         !*self.world.resource::<ShouldQuit>()
     }
@@ -0,0 +1,153 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::ai::Behavior;
+use crate::level_manager::LevelEntry;
+use crate::resources::DEFAULT_TICK_RATE;
+
+/// Lists the game's content, so that adding a level doesn't require a recompile.
+#[derive(Deserialize)]
+pub struct Manifest {
+    pub font: PathBuf,
+    pub levels: Vec<LevelEntry>,
+    /// NPC behaviors, referred to by the `behavior` of `npc` entities.
+    #[serde(default)]
+    pub behaviors: HashMap<String, Behavior>,
+    /// Ticks of the game simulation per second.
+    #[serde(default = "default_tick_rate")]
+    pub tick_rate: u32,
+    /// Seeds the `Rng` and runs the game simulation deterministically, so that the same input
+    /// always gives the same results. Without one, the `Rng` is seeded from the clock.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+fn default_tick_rate() -> u32 {
+    DEFAULT_TICK_RATE
+}
+
+#[derive(Debug)]
+pub enum ManifestError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        source: ron::error::SpannedError,
+    },
+    NoLevels,
+    InvalidTickRate,
+    InvalidId(String),
+    DuplicateId(String),
+    InvalidTileSize {
+        id: String,
+    },
+    MissingFile {
+        field: &'static str,
+        id: Option<String>,
+        path: PathBuf,
+    },
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, source } => write!(f, "couldn't read {}: {}", path.display(), source),
+            Self::Parse { path, source } => write!(f, "{}:{}", path.display(), source),
+            Self::NoLevels => write!(f, "no levels listed"),
+            Self::InvalidTickRate => write!(f, "tick rate must be above zero"),
+            Self::InvalidId(id) => write!(
+                f,
+                "level id \"{}\" must be non-empty and contain no whitespace",
+                id
+            ),
+            Self::DuplicateId(id) => write!(f, "level id \"{}\" is listed twice", id),
+            Self::InvalidTileSize { id } => {
+                write!(f, "level \"{}\" has a tile size of zero", id)
+            }
+            Self::MissingFile { field, id, path } => match id {
+                Some(id) => write!(
+                    f,
+                    "{} of level \"{}\" not found: {}",
+                    field,
+                    id,
+                    path.display()
+                ),
+                None => write!(f, "{} not found: {}", field, path.display()),
+            },
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            Self::Parse { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+fn check_file(field: &'static str, id: Option<&str>, path: &Path) -> Result<(), ManifestError> {
+    if path.is_file() {
+        Ok(())
+    } else {
+        Err(ManifestError::MissingFile {
+            field,
+            id: id.map(str::to_owned),
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Manifest {
+    fn validate(&self) -> Result<(), ManifestError> {
+        check_file("font", None, &self.font)?;
+        if self.levels.is_empty() {
+            return Err(ManifestError::NoLevels);
+        }
+        if self.tick_rate == 0 {
+            return Err(ManifestError::InvalidTickRate);
+        }
+        let mut ids = HashSet::new();
+        for level in &self.levels {
+            let id = level.id.as_str();
+            if id.is_empty() || id.contains(char::is_whitespace) {
+                return Err(ManifestError::InvalidId(level.id.clone()));
+            }
+            if !ids.insert(id) {
+                return Err(ManifestError::DuplicateId(level.id.clone()));
+            }
+            if level.tile_size.0 == 0 || level.tile_size.1 == 0 {
+                return Err(ManifestError::InvalidTileSize {
+                    id: level.id.clone(),
+                });
+            }
+            check_file("map", Some(id), &level.map)?;
+            check_file("spritesheet", Some(id), &level.spritesheet)?;
+            if let Some(music) = &level.music {
+                check_file("music", Some(id), music)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn load_manifest(path: &Path) -> Result<Manifest, ManifestError> {
+    let text = fs::read_to_string(path).map_err(|source| ManifestError::Io {
+        path: path.to_owned(),
+        source,
+    })?;
+    let manifest: Manifest = ron::from_str(&text).map_err(|source| ManifestError::Parse {
+        path: path.to_owned(),
+        source,
+    })?;
+    manifest.validate()?;
+    Ok(manifest)
+}
@@ -57,3 +57,24 @@ pub fn create_centered_button(
     let result_handle = engine.resource_manager.add_image(full_image);
     (result_handle, full_image_bounds)
 }
+
+pub fn create_centered_text(
+    engine: &mut Engine,
+    font_handle: FontHandle,
+    text: &str,
+    y_off: i32,
+) -> (ImageHandle, Rect) {
+    let font = engine.resource_manager.get_font(font_handle).unwrap();
+    let text_image = draw_text_to_image(
+        font,
+        &mut engine.font_helper.default_layout,
+        text,
+        20.0,
+        WHITE,
+    );
+    let mut bounds = Rect::new(Vec2::new(0, 0), text_image.width(), text_image.height());
+    let center_x = (SCREEN_WIDTH.saturating_sub(text_image.width()) / 2) as i32;
+    bounds.offset(Vec2::new(center_x, y_off));
+    let result_handle = engine.resource_manager.add_image(text_image);
+    (result_handle, bounds)
+}
@@ -0,0 +1,506 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::str::FromStr;
+
+use engine::types::{Vec2, Vec2F};
+
+use crate::components::Collider;
+use crate::raycast::body_can_move;
+use crate::resources::Level;
+use crate::tile_grid::TileGrid;
+
+/// Cost of moving one tile orthogonally, scaled so that diagonal steps can cost about √2 of it.
+pub const ORTHOGONAL_COST: u32 = 10;
+pub const DIAGONAL_COST: u32 = 14;
+/// Metadata key selecting the `Connectivity` of a level, `4` or `8`.
+pub const CONNECTIVITY_KEY: &str = "connectivity";
+
+const STEPS: [(Vec2, u32); 8] = [
+    (Vec2::new(0, -1), ORTHOGONAL_COST),
+    (Vec2::new(1, 0), ORTHOGONAL_COST),
+    (Vec2::new(0, 1), ORTHOGONAL_COST),
+    (Vec2::new(-1, 0), ORTHOGONAL_COST),
+    (Vec2::new(1, -1), DIAGONAL_COST),
+    (Vec2::new(1, 1), DIAGONAL_COST),
+    (Vec2::new(-1, 1), DIAGONAL_COST),
+    (Vec2::new(-1, -1), DIAGONAL_COST),
+];
+
+/// Which neighbouring tiles a path can step to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Connectivity {
+    Four,
+    /// Diagonal steps are also allowed, as long as they don't cut a corner.
+    Eight,
+}
+
+impl Connectivity {
+    /// Offsets to the neighbouring tiles and the cost of stepping to them.
+    pub fn steps(self) -> &'static [(Vec2, u32)] {
+        match self {
+            Self::Four => &STEPS[..4],
+            Self::Eight => &STEPS,
+        }
+    }
+}
+
+impl FromStr for Connectivity {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "4" => Ok(Self::Four),
+            "8" => Ok(Self::Eight),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Whether a step by `offset` from `pos` lands on a walkable tile. Diagonal steps also need both
+/// orthogonal tiles they pass between to be walkable, so that a body doesn't clip the corner.
+pub fn can_step(pos: Vec2, offset: Vec2, walkable: impl Fn(Vec2) -> bool) -> bool {
+    walkable(Vec2::new(pos.x + offset.x, pos.y + offset.y))
+        && (offset.x == 0
+            || offset.y == 0
+            || (walkable(Vec2::new(pos.x + offset.x, pos.y))
+                && walkable(Vec2::new(pos.x, pos.y + offset.y))))
+}
+
+/// The tiles a body covers relative to the tile its position is on, as the first and last
+/// columns and rows.
+#[derive(Clone, Copy)]
+pub struct Footprint {
+    pub first: Vec2,
+    pub last: Vec2,
+}
+
+impl Footprint {
+    /// A body that fits on a single tile.
+    pub const TILE: Self = Self {
+        first: Vec2::new(0, 0),
+        last: Vec2::new(0, 0),
+    };
+    /// Whether every tile the body covers when standing on `pos` is walkable.
+    pub fn fits(self, pos: Vec2, walkable: impl Fn(Vec2) -> bool) -> bool {
+        (self.first.y..=self.last.y).all(|y| {
+            (self.first.x..=self.last.x).all(|x| walkable(Vec2::new(pos.x + x, pos.y + y)))
+        })
+    }
+}
+
+impl Default for Footprint {
+    fn default() -> Self {
+        Self::TILE
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Cell {
+    /// The wave that last reached this tile; the distance is stale for any other wave.
+    wave: u32,
+    distance: u32,
+}
+
+/// Path costs from a single origin over the walkable tiles of a level, rebuilt each time the
+/// origin moves. Costs are in `ORTHOGONAL_COST`s per tile. Tiles reached by an older wave are
+/// treated as unreached, so nothing is cleared between waves, and the queues are kept between
+/// waves so propagating doesn't allocate.
+pub struct FlowField {
+    cells: TileGrid<Cell>,
+    wave: u32,
+    /// Breadth first frontier for four-connected waves, where every step costs the same.
+    queue: VecDeque<(Vec2, u32)>,
+    /// Frontier ordered by cost for eight-connected waves, as `(cost, x, y)`.
+    heap: BinaryHeap<Reverse<(u32, i32, i32)>>,
+}
+
+impl FlowField {
+    pub fn new(walkable: impl Iterator<Item = Vec2>) -> Self {
+        let cells: TileGrid<Cell> = walkable.map(|pos| (pos, Cell::default())).collect();
+        let queue = VecDeque::with_capacity(cells.len());
+        let heap = BinaryHeap::with_capacity(cells.len());
+        Self {
+            cells,
+            wave: 0,
+            queue,
+            heap,
+        }
+    }
+    pub fn is_walkable(&self, pos: Vec2) -> bool {
+        self.cells.contains(pos)
+    }
+    /// The cost from the origin of the last wave, `None` if it didn't reach `pos`.
+    pub fn distance(&self, pos: Vec2) -> Option<u32> {
+        self.cells
+            .get(pos)
+            .filter(|cell| cell.wave == self.wave && self.wave != 0)
+            .map(|cell| cell.distance)
+    }
+    /// Spreads a new wave from `origin`, stopping `limit` tiles away from it if given.
+    pub fn propagate(&mut self, origin: Vec2, limit: Option<u32>, connectivity: Connectivity) {
+        self.wave = match self.wave.checked_add(1) {
+            Some(wave) => wave,
+            None => {
+                // Every stamp is about to become ambiguous, so start over.
+                for (_, cell) in self.cells.iter_mut() {
+                    cell.wave = 0;
+                }
+                1
+            }
+        };
+        let limit = limit.map(|limit| limit.saturating_mul(ORTHOGONAL_COST));
+        if !self.visit(origin, 0) {
+            return;
+        }
+        match connectivity {
+            Connectivity::Four => self.propagate_breadth_first(origin, limit),
+            Connectivity::Eight => self.propagate_by_cost(origin, limit),
+        }
+    }
+    fn propagate_breadth_first(&mut self, origin: Vec2, limit: Option<u32>) {
+        self.queue.clear();
+        self.queue.push_back((origin, 0));
+        while let Some((pos, distance)) = self.queue.pop_front() {
+            if limit.map_or(false, |limit| distance >= limit) {
+                continue;
+            }
+            for &(offset, cost) in Connectivity::Four.steps() {
+                let next = Vec2::new(pos.x + offset.x, pos.y + offset.y);
+                if self.visit(next, distance + cost) {
+                    self.queue.push_back((next, distance + cost));
+                }
+            }
+        }
+    }
+    /// Dijkstra's algorithm, since diagonal steps cost more than orthogonal ones.
+    fn propagate_by_cost(&mut self, origin: Vec2, limit: Option<u32>) {
+        self.heap.clear();
+        self.heap.push(Reverse((0, origin.x, origin.y)));
+        while let Some(Reverse((distance, x, y))) = self.heap.pop() {
+            let pos = Vec2::new(x, y);
+            if self.distance(pos) != Some(distance) {
+                // A cheaper route to this tile was found after this one was queued.
+                continue;
+            }
+            if limit.map_or(false, |limit| distance >= limit) {
+                continue;
+            }
+            for &(offset, cost) in Connectivity::Eight.steps() {
+                if !can_step(pos, offset, |tile| self.is_walkable(tile)) {
+                    continue;
+                }
+                let next = Vec2::new(pos.x + offset.x, pos.y + offset.y);
+                if self.visit(next, distance + cost) {
+                    self.heap.push(Reverse((distance + cost, next.x, next.y)));
+                }
+            }
+        }
+    }
+    /// Records `distance` for a walkable tile if this wave hasn't reached it more cheaply,
+    /// returning whether it did.
+    fn visit(&mut self, pos: Vec2, distance: u32) -> bool {
+        let wave = self.wave;
+        match self.cells.get_mut(pos) {
+            Some(cell) if cell.wave != wave || cell.distance > distance => {
+                *cell = Cell { wave, distance };
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Estimates the remaining cost of a path for `find_path`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Heuristic {
+    Manhattan,
+    /// Exact on open ground when diagonal steps are allowed, and never more than `Manhattan`.
+    Octile,
+}
+
+impl Heuristic {
+    pub fn estimate(self, from: Vec2, to: Vec2) -> u32 {
+        let dx = from.x.abs_diff(to.x);
+        let dy = from.y.abs_diff(to.y);
+        match self {
+            Self::Manhattan => ORTHOGONAL_COST * (dx + dy),
+            Self::Octile => {
+                ORTHOGONAL_COST * dx.max(dy) + (DIAGONAL_COST - ORTHOGONAL_COST) * dx.min(dy)
+            }
+        }
+    }
+}
+
+/// Tiles an agent can stand on: those with a background tile and no collision.
+pub fn is_walkable(level: &Level, pos: Vec2) -> bool {
+    level.background_tiles.contains(pos) && !level.collision.contains(pos)
+}
+
+/// Finds the cheapest path between two tiles with A*, returning every tile along it, `start` and
+/// `goal` included. Only tiles where a body with the given `Footprint` fits are stepped on.
+/// `Manhattan` overestimates diagonal steps, so with `Connectivity::Eight` its paths may not be
+/// the cheapest.
+pub fn find_path(
+    level: &Level,
+    start: Vec2,
+    goal: Vec2,
+    heuristic: Heuristic,
+    connectivity: Connectivity,
+    footprint: Footprint,
+) -> Option<Vec<Vec2>> {
+    let fits = |tile| footprint.fits(tile, |tile| is_walkable(level, tile));
+    if !fits(start) || !fits(goal) {
+        return None;
+    }
+    // Cheapest known cost to each tile and the tile it was reached from.
+    let mut visited: HashMap<Vec2, (u32, Option<Vec2>)> = HashMap::new();
+    // Ordered by estimated total cost, then by estimate so ties expand toward the goal. `Vec2`
+    // isn't `Ord`, so the heap holds indices into `positions`.
+    let mut open = BinaryHeap::new();
+    let mut positions = Vec::new();
+    visited.insert(start, (0, None));
+    open.push(Reverse((heuristic.estimate(start, goal), 0, 0)));
+    positions.push((start, 0));
+    while let Some(Reverse((_, _, index))) = open.pop() {
+        let (pos, cost) = positions[index];
+        if pos == goal {
+            let mut path = vec![goal];
+            let mut current = goal;
+            while let Some((_, Some(previous))) = visited.get(&current) {
+                path.push(*previous);
+                current = *previous;
+            }
+            path.reverse();
+            return Some(path);
+        }
+        if visited[&pos].0 < cost {
+            // A cheaper route to this tile was queued after this one.
+            continue;
+        }
+        for &(offset, step_cost) in connectivity.steps() {
+            if !can_step(pos, offset, fits) {
+                continue;
+            }
+            let next = Vec2::new(pos.x + offset.x, pos.y + offset.y);
+            let next_cost = cost + step_cost;
+            if visited
+                .get(&next)
+                .map_or(false, |(known, _)| *known <= next_cost)
+            {
+                continue;
+            }
+            visited.insert(next, (next_cost, Some(pos)));
+            let estimate = heuristic.estimate(next, goal);
+            open.push(Reverse((next_cost + estimate, estimate, positions.len())));
+            positions.push((next, next_cost));
+        }
+    }
+    None
+}
+
+/// Removes the waypoints a body could skip by moving straight to a later one, keeping the first
+/// and last points.
+pub fn smooth_path(level: &Level, collider: &Collider, points: &[Vec2F]) -> Vec<Vec2F> {
+    let mut smoothed = Vec::with_capacity(points.len());
+    let mut anchor = 0;
+    if let Some(first) = points.first() {
+        smoothed.push(*first);
+    }
+    while anchor + 1 < points.len() {
+        let mut furthest = anchor + 1;
+        while furthest + 1 < points.len()
+            && body_can_move(level, collider, points[anchor], points[furthest + 1])
+        {
+            furthest += 1;
+        }
+        smoothed.push(points[furthest]);
+        anchor = furthest;
+    }
+    smoothed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    /// A level drawn row by row: `.` is floor, `#` a wall and anything else void.
+    fn level(rows: &[&str]) -> Level {
+        let mut background = HashMap::new();
+        let mut collision = HashSet::new();
+        for (y, row) in rows.iter().enumerate() {
+            for (x, tile) in row.chars().enumerate() {
+                let pos = Vec2::new(x as i32, y as i32);
+                if tile == '.' || tile == '#' {
+                    background.insert(pos, Vec2::new(0, 0));
+                }
+                if tile == '#' {
+                    collision.insert(pos);
+                }
+            }
+        }
+        Level::new("test")
+            .background_tiles(background)
+            .collision(collision)
+            .build()
+    }
+
+    fn path(level: &Level, start: (i32, i32), goal: (i32, i32)) -> Option<Vec<(i32, i32)>> {
+        find_path(
+            level,
+            Vec2::new(start.0, start.1),
+            Vec2::new(goal.0, goal.1),
+            Heuristic::Manhattan,
+            Connectivity::Four,
+            Footprint::TILE,
+        )
+        .map(|path| path.iter().map(|pos| (pos.x, pos.y)).collect())
+    }
+
+    #[test]
+    fn paths_detour_around_walls() {
+        let level = level(&[
+            "..#..", //
+            "..#..", ".....",
+        ]);
+        let path = path(&level, (0, 0), (4, 0)).unwrap();
+        assert_eq!(path.first(), Some(&(0, 0)));
+        assert_eq!(path.last(), Some(&(4, 0)));
+        // The shortest way round goes along the bottom row, under the wall.
+        assert_eq!(path.len(), 9);
+        assert!(path.contains(&(2, 2)));
+        for pair in path.windows(2) {
+            let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+            assert_eq!((x0 - x1).abs() + (y0 - y1).abs(), 1);
+            assert!(is_walkable(&level, Vec2::new(x1, y1)));
+        }
+    }
+
+    #[test]
+    fn unreachable_goals_have_no_path() {
+        let level = level(&[
+            "...#.", //
+            "...##", ".....",
+        ]);
+        assert_eq!(path(&level, (0, 0), (4, 0)), None);
+        // Walls and void can't be stood on at all.
+        assert_eq!(path(&level, (0, 0), (3, 0)), None);
+        assert_eq!(path(&level, (0, 0), (9, 9)), None);
+    }
+
+    #[test]
+    fn large_bodies_dont_fit_through_narrow_gaps() {
+        let level = level(&[
+            "..#..", //
+            ".....", "..#..",
+        ]);
+        let (start, goal) = (Vec2::new(0, 0), Vec2::new(3, 0));
+        let find = |footprint| {
+            find_path(
+                &level,
+                start,
+                goal,
+                Heuristic::Manhattan,
+                Connectivity::Four,
+                footprint,
+            )
+        };
+        assert!(find(Footprint::TILE).is_some());
+        let two_by_two = Footprint {
+            first: Vec2::new(0, 0),
+            last: Vec2::new(1, 1),
+        };
+        assert!(find(two_by_two).is_none());
+    }
+
+    #[test]
+    fn eight_connected_paths_take_the_diagonal() {
+        let level = level(&[
+            "....", //
+            "....", "....", "....",
+        ]);
+        let path = find_path(
+            &level,
+            Vec2::new(0, 0),
+            Vec2::new(3, 3),
+            Heuristic::Octile,
+            Connectivity::Eight,
+            Footprint::TILE,
+        )
+        .unwrap();
+        assert_eq!(path.len(), 4);
+    }
+
+    fn propagated(
+        level: &Level,
+        origin: (i32, i32),
+        limit: Option<u32>,
+        connectivity: Connectivity,
+    ) -> FlowField {
+        let mut flow_field = FlowField::new(
+            level
+                .background_tiles
+                .keys()
+                .filter(|pos| is_walkable(level, *pos)),
+        );
+        flow_field.propagate(Vec2::new(origin.0, origin.1), limit, connectivity);
+        flow_field
+    }
+
+    #[test]
+    fn waves_stop_at_the_limit() {
+        let level = level(&[".........."]);
+        for connectivity in [Connectivity::Four, Connectivity::Eight] {
+            let flow_field = propagated(&level, (0, 0), Some(3), connectivity);
+            assert_eq!(
+                flow_field.distance(Vec2::new(3, 0)),
+                Some(3 * ORTHOGONAL_COST)
+            );
+            assert_eq!(flow_field.distance(Vec2::new(4, 0)), None);
+            let flow_field = propagated(&level, (0, 0), None, connectivity);
+            assert_eq!(
+                flow_field.distance(Vec2::new(9, 0)),
+                Some(9 * ORTHOGONAL_COST)
+            );
+        }
+    }
+
+    #[test]
+    fn diagonal_steps_dont_cut_blocked_corners() {
+        let open = level(&[
+            "..", //
+            "..",
+        ]);
+        let flow_field = propagated(&open, (0, 0), None, Connectivity::Eight);
+        assert_eq!(flow_field.distance(Vec2::new(1, 1)), Some(DIAGONAL_COST));
+        let corner = level(&[
+            ".#", //
+            "..",
+        ]);
+        let flow_field = propagated(&corner, (0, 0), None, Connectivity::Eight);
+        assert_eq!(
+            flow_field.distance(Vec2::new(1, 1)),
+            Some(2 * ORTHOGONAL_COST)
+        );
+        let path = find_path(
+            &corner,
+            Vec2::new(0, 0),
+            Vec2::new(1, 1),
+            Heuristic::Octile,
+            Connectivity::Eight,
+            Footprint::TILE,
+        )
+        .unwrap();
+        assert_eq!(path.len(), 3);
+    }
+
+    #[test]
+    fn new_waves_replace_old_distances() {
+        let level = level(&["....."]);
+        let mut flow_field = propagated(&level, (0, 0), None, Connectivity::Four);
+        flow_field.propagate(Vec2::new(4, 0), Some(1), Connectivity::Four);
+        assert_eq!(flow_field.distance(Vec2::new(4, 0)), Some(0));
+        // Reached by the first wave only.
+        assert_eq!(flow_field.distance(Vec2::new(0, 0)), None);
+    }
+}
@@ -0,0 +1,227 @@
+use engine::types::{Vec2, Vec2F};
+
+use crate::collision::SKIN;
+use crate::components::Collider;
+use crate::pathfinding::is_walkable;
+use crate::resources::Level;
+use crate::util::tile_of;
+
+/// The tiles a segment passes through, in order from its start, found with a DDA walk over the
+/// tile grid.
+pub struct GridTraversal {
+    tile: Vec2,
+    end: Vec2,
+    step: Vec2,
+    /// How far along the segment, from 0 to 1, the next vertical and horizontal tile borders are.
+    next_border: (f32, f32),
+    /// How far along the segment a whole tile is, horizontally and vertically.
+    tile_span: (f32, f32),
+    /// How far along the segment the current tile was entered.
+    entered: f32,
+    started: bool,
+}
+
+impl GridTraversal {
+    pub fn new(from: Vec2F, to: Vec2F) -> Self {
+        let tile = tile_of(from);
+        let axis = |from: f32, to: f32, tile: i32| {
+            let delta = to - from;
+            if delta > 0.0 {
+                (1, (tile as f32 + 1.0 - from) / delta, 1.0 / delta)
+            } else if delta < 0.0 {
+                (-1, (from - tile as f32) / -delta, 1.0 / -delta)
+            } else {
+                (0, f32::INFINITY, f32::INFINITY)
+            }
+        };
+        let (step_x, border_x, span_x) = axis(from.x, to.x, tile.x);
+        let (step_y, border_y, span_y) = axis(from.y, to.y, tile.y);
+        Self {
+            tile,
+            end: tile_of(to),
+            step: Vec2::new(step_x, step_y),
+            next_border: (border_x, border_y),
+            tile_span: (span_x, span_y),
+            entered: 0.0,
+            started: false,
+        }
+    }
+    /// How far along the segment, from 0 to 1, the last returned tile was entered.
+    pub fn entered(&self) -> f32 {
+        self.entered
+    }
+}
+
+impl Iterator for GridTraversal {
+    type Item = Vec2;
+    fn next(&mut self) -> Option<Vec2> {
+        if !self.started {
+            self.started = true;
+            return Some(self.tile);
+        }
+        if self.tile == self.end {
+            return None;
+        }
+        // Rounding can make the walk reach the end column or row early, so don't step past it.
+        let step_x = if self.tile.x == self.end.x {
+            false
+        } else if self.tile.y == self.end.y {
+            true
+        } else {
+            self.next_border.0 < self.next_border.1
+        };
+        if step_x {
+            self.tile.x += self.step.x;
+            self.entered = self.next_border.0;
+            self.next_border.0 += self.tile_span.0;
+        } else {
+            self.tile.y += self.step.y;
+            self.entered = self.next_border.1;
+            self.next_border.1 += self.tile_span.1;
+        }
+        Some(self.tile)
+    }
+}
+
+pub struct RayHit {
+    pub tile: Vec2,
+    /// From the start of the ray to where it entered the tile, in tiles.
+    pub distance: f32,
+}
+
+/// Casts a ray from `from` to `to`, returning the first collision tile it hits.
+pub fn raycast(level: &Level, from: Vec2F, to: Vec2F) -> Option<RayHit> {
+    let length = f32::hypot(to.x - from.x, to.y - from.y);
+    let mut traversal = GridTraversal::new(from, to);
+    while let Some(tile) = traversal.next() {
+        if level.collision.contains(tile) {
+            return Some(RayHit {
+                tile,
+                distance: traversal.entered() * length,
+            });
+        }
+    }
+    None
+}
+
+/// Whether the segment between two points crosses no collision tiles.
+pub fn is_clear(level: &Level, from: Vec2F, to: Vec2F) -> bool {
+    raycast(level, from, to).is_none()
+}
+
+/// Whether the segment between two points stays on walkable tiles, crossing neither collision
+/// nor tiles without a background.
+pub fn is_walkable_between(level: &Level, from: Vec2F, to: Vec2F) -> bool {
+    GridTraversal::new(from, to).all(|tile| is_walkable(level, tile))
+}
+
+/// Whether a body at `from` can see one at `to` within `range` tiles, looking between the
+/// centers of the tiles they stand on.
+pub fn line_of_sight(level: &Level, from: Vec2F, to: Vec2F, range: f32) -> bool {
+    let from = Vec2F::new(from.x + 0.5, from.y + 0.5);
+    let to = Vec2F::new(to.x + 0.5, to.y + 0.5);
+    f32::hypot(to.x - from.x, to.y - from.y) <= range && is_clear(level, from, to)
+}
+
+/// Whether a body can move in a straight line between two positions with its `Collider` staying
+/// on walkable tiles. Lines are walked from points around the bounding box of the collider,
+/// spaced less than a tile apart so that a wall or a gap can't fit between them unnoticed.
+pub fn body_can_move(level: &Level, collider: &Collider, from: Vec2F, to: Vec2F) -> bool {
+    let (min, max) = collider.bounds(Vec2F::new(0.0, 0.0));
+    let samples = |min: f32, max: f32| {
+        let (min, max) = (min + SKIN, max - SKIN);
+        let gaps = (max - min).floor() as i32 + 1;
+        (0..=gaps).map(move |i| min + (max - min) * i as f32 / gaps as f32)
+    };
+    samples(min.y, max.y).all(|y| {
+        samples(min.x, max.x).all(|x| {
+            is_walkable_between(
+                level,
+                Vec2F::new(from.x + x, from.y + y),
+                Vec2F::new(to.x + x, to.y + y),
+            )
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use super::*;
+
+    /// A U of floor around a gap, with a wall at the top of its right arm.
+    ///
+    /// ```text
+    /// .   #
+    /// .   .
+    /// . . .
+    /// ```
+    fn level() -> Level {
+        let floor = [(0, 0), (0, 1), (0, 2), (1, 2), (2, 2), (2, 1), (2, 0)];
+        let background: HashMap<Vec2, Vec2> = floor
+            .iter()
+            .map(|(x, y)| (Vec2::new(*x, *y), Vec2::new(0, 0)))
+            .collect();
+        let collision: HashSet<Vec2> = [Vec2::new(2, 0)].into_iter().collect();
+        Level::new("test")
+            .background_tiles(background)
+            .collision(collision)
+            .build()
+    }
+
+    fn tiles(from: Vec2F, to: Vec2F) -> Vec<(i32, i32)> {
+        GridTraversal::new(from, to)
+            .map(|tile| (tile.x, tile.y))
+            .collect()
+    }
+
+    #[test]
+    fn traversal_visits_every_crossed_tile_in_order() {
+        assert_eq!(
+            tiles(Vec2F::new(0.5, 0.5), Vec2F::new(2.5, 1.5)),
+            vec![(0, 0), (1, 0), (1, 1), (2, 1)]
+        );
+        assert_eq!(
+            tiles(Vec2F::new(2.5, 0.5), Vec2F::new(-0.5, 0.5)),
+            vec![(2, 0), (1, 0), (0, 0), (-1, 0)]
+        );
+        assert_eq!(
+            tiles(Vec2F::new(0.2, 0.2), Vec2F::new(0.8, 0.9)),
+            vec![(0, 0)]
+        );
+    }
+
+    #[test]
+    fn raycast_stops_at_the_first_collision_tile() {
+        let level = level();
+        let hit = raycast(&level, Vec2F::new(0.5, 0.5), Vec2F::new(3.5, 0.5)).unwrap();
+        assert_eq!((hit.tile.x, hit.tile.y), (2, 0));
+        assert!((hit.distance - 1.5).abs() < 1e-5);
+        assert!(is_clear(&level, Vec2F::new(0.5, 0.5), Vec2F::new(0.5, 2.5)));
+    }
+
+    #[test]
+    fn bodies_only_move_over_walkable_tiles() {
+        let level = level();
+        let collider = Collider::default();
+        let at = |x: f32, y: f32| Vec2F::new(x, y);
+        assert!(body_can_move(&level, &collider, at(0.0, 0.0), at(0.0, 2.0)));
+        assert!(body_can_move(&level, &collider, at(0.0, 2.0), at(2.0, 2.0)));
+        assert!(body_can_move(&level, &collider, at(2.0, 2.0), at(2.0, 1.0)));
+        // Across the gap, which has no collision but no floor either.
+        assert!(!body_can_move(
+            &level,
+            &collider,
+            at(0.0, 1.0),
+            at(2.0, 1.0)
+        ));
+        // Into the wall.
+        assert!(!body_can_move(
+            &level,
+            &collider,
+            at(2.0, 2.0),
+            at(2.0, 0.0)
+        ));
+    }
+}
@@ -2,6 +2,7 @@ use engine::drawing::{blit_rect, blit_rect_with_alpha, blit_with_alpha, draw_rec
 use engine::types::{Color, Rect, Vec2, Vec2F};
 use engine::{Engine, Screen};
 
+use crate::components::Collider;
 use crate::resources::*;
 
 pub fn render_tiles(
@@ -12,13 +13,13 @@ pub fn render_tiles(
     tile_dim: Vec2,
     engine: &mut Engine,
 ) {
-    let spritesheet = engine
-        .resource_manager
-        .get_image(level.spritesheet_handle)
-        .unwrap();
+    let spritesheet = match level.spritesheet_handle {
+        Some(handle) => engine.resource_manager.get_image(handle).unwrap(),
+        None => return,
+    };
     for x in -1..(visible_tiles.x + 1) {
         for y in -1..(visible_tiles.y + 1) {
-            if let Some(tile) = level.background_tiles.get(&Vec2::new(
+            if let Some(tile) = level.background_tiles.get(Vec2::new(
                 x + camera_offset.x as i32,
                 y + camera_offset.y as i32,
             )) {
@@ -37,7 +38,7 @@ pub fn render_tiles(
     }
     for x in -1..(visible_tiles.x + 1) {
         for y in -1..(visible_tiles.y + 1) {
-            if let Some(tile) = level.foreground_tiles.get(&Vec2::new(
+            if let Some(tile) = level.foreground_tiles.get(Vec2::new(
                 x + camera_offset.x as i32,
                 y + camera_offset.y as i32,
             )) {
@@ -65,14 +66,10 @@ pub fn render_collision(
 ) {
     for x in -1..(visible_tiles.x + 1) {
         for y in -1..(visible_tiles.y + 1) {
-            if level
-                .collision
-                .get(&Vec2::new(
-                    x + camera_offset.x as i32,
-                    y + camera_offset.y as i32,
-                ))
-                .is_some()
-            {
+            if level.collision.contains(Vec2::new(
+                x + camera_offset.x as i32,
+                y + camera_offset.y as i32,
+            )) {
                 let collision_rect = Rect::new(
                     Vec2::new(
                         (x as f32 * tile_dim.x as f32 - tile_offset.x) as i32,
@@ -90,27 +87,38 @@ pub fn render_collision(
         }
     }
 }
-pub fn render_player(player_pos: Vec2F, camera_offset: Vec2F, tile_dim: Vec2, screen: &mut Screen) {
-    let player_rect = Rect::new(
+/// The bounding box of a `Collider` on the screen. Circles are drawn as their bounding box too.
+fn collider_rect(pos: Vec2F, collider: &Collider, camera_offset: Vec2F, tile_dim: Vec2) -> Rect {
+    let (min, max) = collider.bounds(pos);
+    Rect::new(
         Vec2::new(
-            ((player_pos.x - camera_offset.x) * tile_dim.x as f32) as i32,
-            ((player_pos.y - camera_offset.y) * tile_dim.y as f32) as i32,
+            ((min.x - camera_offset.x) * tile_dim.x as f32) as i32,
+            ((min.y - camera_offset.y) * tile_dim.y as f32) as i32,
         ),
-        tile_dim.x as u32,
-        tile_dim.y as u32,
-    );
+        ((max.x - min.x) * tile_dim.x as f32) as u32,
+        ((max.y - min.y) * tile_dim.y as f32) as u32,
+    )
+}
+
+pub fn render_player(
+    player_pos: Vec2F,
+    collider: &Collider,
+    camera_offset: Vec2F,
+    tile_dim: Vec2,
+    screen: &mut Screen,
+) {
+    let player_rect = collider_rect(player_pos, collider, camera_offset, tile_dim);
     draw_rectangle(player_rect, screen, Color::new(255, 255, 255, 255));
 }
 
-pub fn render_enemy(pos: Vec2F, camera_offset: Vec2F, tile_dim: Vec2, screen: &mut Screen) {
-    let rect = Rect::new(
-        Vec2::new(
-            ((pos.x - camera_offset.x) * tile_dim.x as f32) as i32,
-            ((pos.y - camera_offset.y) * tile_dim.y as f32) as i32,
-        ),
-        tile_dim.x as u32,
-        tile_dim.y as u32,
-    );
+pub fn render_enemy(
+    pos: Vec2F,
+    collider: &Collider,
+    camera_offset: Vec2F,
+    tile_dim: Vec2,
+    screen: &mut Screen,
+) {
+    let rect = collider_rect(pos, collider, camera_offset, tile_dim);
     draw_rectangle(rect, screen, Color::new(255, 0, 0, 255));
 }
 
@@ -134,3 +142,23 @@ pub fn render_main_menu(resources: &MainMenuResources, engine: &mut Engine) {
         resources.button_quit_bounds.top_left,
     );
 }
+
+pub fn render_error_screen(
+    resources: &ErrorScreenResources,
+    menu_resources: &MainMenuResources,
+    engine: &mut Engine,
+) {
+    for (handle, bounds) in &resources.lines {
+        let line = engine.resource_manager.get_image(*handle).unwrap();
+        blit_with_alpha(line, &mut engine.screen, bounds.top_left);
+    }
+    let btn_quit = engine
+        .resource_manager
+        .get_image(menu_resources.button_quit_handle)
+        .unwrap();
+    blit_with_alpha(
+        btn_quit,
+        &mut engine.screen,
+        menu_resources.button_quit_bounds.top_left,
+    );
+}
@@ -1,50 +1,215 @@
 use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
-use engine::resource::ImageHandle;
+use engine::resource::{FontHandle, ImageHandle};
 use engine::types::{Rect, Vec2, Vec2F, VirtualKeyCode};
 
+use crate::components::Facing;
+use crate::entity_def::EntityDef;
+use crate::pathfinding::{Connectivity, FlowField, CONNECTIVITY_KEY};
+use crate::tile_grid::TileGrid;
+
+pub const DEFAULT_TILE_DIM: Vec2 = Vec2::new(32, 32);
+/// Metadata key holding the tile size, written as `<width>x<height>` in pixels.
+pub const TILE_SIZE_KEY: &str = "tile_size";
+
+pub fn parse_tile_size(value: &str) -> Option<Vec2> {
+    let (width, height) = value.split_once('x')?;
+    let width: i32 = width.parse().ok()?;
+    let height: i32 = height.parse().ok()?;
+    if width > 0 && height > 0 {
+        Some(Vec2::new(width, height))
+    } else {
+        None
+    }
+}
+
+pub fn format_tile_size(tile_dim: Vec2) -> String {
+    format!("{}x{}", tile_dim.x, tile_dim.y)
+}
+
+/// The smallest rectangle of tiles containing every tile of a level.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct LevelBounds {
+    pub origin: Vec2,
+    /// Width and height in tiles.
+    pub size: Vec2,
+}
+
+impl LevelBounds {
+    pub fn from_tiles<'a>(mut tiles: impl Iterator<Item = &'a Vec2>) -> Option<Self> {
+        let first = *tiles.next()?;
+        let (mut min, mut max) = (first, first);
+        for tile in tiles {
+            min.x = min.x.min(tile.x);
+            min.y = min.y.min(tile.y);
+            max.x = max.x.max(tile.x);
+            max.y = max.y.max(tile.y);
+        }
+        Some(Self {
+            origin: min,
+            size: Vec2::new(max.x - min.x + 1, max.y - min.y + 1),
+        })
+    }
+    /// The last tile inside the bounds, inclusive.
+    pub fn max(&self) -> Vec2 {
+        Vec2::new(
+            self.origin.x + self.size.x - 1,
+            self.origin.y + self.size.y - 1,
+        )
+    }
+    pub fn contains(&self, tile: Vec2) -> bool {
+        let max = self.max();
+        tile.x >= self.origin.x && tile.x <= max.x && tile.y >= self.origin.y && tile.y <= max.y
+    }
+    pub fn clamp(&self, tile: Vec2) -> Vec2 {
+        let max = self.max();
+        Vec2::new(
+            tile.x.clamp(self.origin.x, max.x),
+            tile.y.clamp(self.origin.y, max.y),
+        )
+    }
+    /// Clamps the top left corner of a view, both in tiles, so that the view stays inside the
+    /// bounds. A view larger than the bounds is centered on them instead.
+    pub fn clamp_view(&self, offset: Vec2F, view: Vec2F) -> Vec2F {
+        let clamp_axis = |offset: f32, view: f32, origin: i32, size: i32| {
+            let (origin, size) = (origin as f32, size as f32);
+            if view >= size {
+                origin - (view - size) / 2.0
+            } else {
+                offset.clamp(origin, origin + size - view)
+            }
+        };
+        Vec2F::new(
+            clamp_axis(offset.x, view.x, self.origin.x, self.size.x),
+            clamp_axis(offset.y, view.y, self.origin.y, self.size.y),
+        )
+    }
+    /// Every tile inside the bounds, in row order.
+    pub fn tiles(&self) -> impl Iterator<Item = Vec2> {
+        let origin = self.origin;
+        let size = self.size;
+        (origin.y..origin.y + size.y)
+            .flat_map(move |y| (origin.x..origin.x + size.x).map(move |x| Vec2::new(x, y)))
+    }
+}
+
 pub struct Level {
     pub name: String,
-    pub spritesheet_handle: ImageHandle,
-    pub dimensions: Vec2,
-    pub background_tiles: HashMap<Vec2, Vec2>,
-    pub foreground_tiles: HashMap<Vec2, Vec2>,
-    pub collision: HashSet<Vec2>,
-    pub entities: HashMap<Vec2, String>,
-    pub bfs_flow_field_z: HashMap<Vec2, i16>,
+    pub spritesheet_handle: Option<ImageHandle>,
+    /// Size of a tile in pixels, both on screen and in the spritesheet.
+    pub tile_dim: Vec2,
+    pub bounds: LevelBounds,
+    pub background_tiles: TileGrid<Vec2>,
+    pub foreground_tiles: TileGrid<Vec2>,
+    pub collision: TileGrid<()>,
+    pub entities: HashMap<Vec2, EntityDef>,
+    pub metadata: HashMap<String, String>,
+    /// Taken from the `connectivity` metadata, four-connected by default.
+    pub connectivity: Connectivity,
+    pub flow_field: FlowField,
 }
 
 impl Level {
-    pub fn new(name: &str, handle: ImageHandle) -> LevelBuilder {
+    pub fn new(name: &str) -> LevelBuilder {
         LevelBuilder {
             name: name.to_owned(),
-            spritesheet_handle: handle,
+            spritesheet_handle: None,
+            tile_dim: None,
             background_tiles: None,
             foreground_tiles: None,
             collision: None,
             entities: None,
+            metadata: None,
         }
     }
-    pub fn reset_flow_field(&mut self) {
-        self.bfs_flow_field_z =
-            HashMap::from_iter(self.background_tiles.keys().map(|key| (*key, 0_i16)));
-        for pos in &self.collision {
-            self.bfs_flow_field_z.insert(*pos, -1);
-        }
+    pub fn entity(&self, pos: Vec2) -> Option<&EntityDef> {
+        self.entities.get(&pos)
+    }
+    // Entities are validated when they are loaded, so their typed properties always parse, and
+    // these are only `None` when there is no entity at `pos` or it doesn't set the property.
+
+    /// The `speed` of the entity at `pos`, in tiles per second.
+    pub fn entity_speed(&self, pos: Vec2) -> Option<f32> {
+        self.entity(pos)?.speed().unwrap()
+    }
+    /// The `aggro` distance of the entity at `pos`, in tiles.
+    pub fn entity_aggro_distance(&self, pos: Vec2) -> Option<f32> {
+        self.entity(pos)?.aggro_distance().unwrap()
+    }
+    /// The id of the patrol route the entity at `pos` follows.
+    pub fn entity_patrol_route(&self, pos: Vec2) -> Option<&str> {
+        self.entity(pos)?.patrol_route()
+    }
+    /// The direction the entity at `pos` is placed looking in.
+    pub fn entity_facing(&self, pos: Vec2) -> Option<Facing> {
+        self.entity(pos)?.facing().unwrap()
+    }
+    /// The id of the dialogue the entity at `pos` starts when talked to.
+    pub fn entity_dialogue_id(&self, pos: Vec2) -> Option<&str> {
+        self.entity(pos)?.dialogue_id()
+    }
+    pub fn entities_of_kind<'a>(
+        &'a self,
+        kind: &'a str,
+    ) -> impl Iterator<Item = (Vec2, &'a EntityDef)> + 'a {
+        self.entities
+            .iter()
+            .filter(move |(_, def)| def.kind() == kind)
+            .map(|(pos, def)| (*pos, def))
+    }
+    /// The waypoints of a patrol route, in order.
+    pub fn patrol_route(&self, route: &str) -> Vec<Vec2> {
+        let mut waypoints: Vec<(u32, Vec2)> = self
+            .entities
+            .iter()
+            .filter_map(|(pos, def)| match def.patrol_waypoint() {
+                Some((waypoint_route, index)) if waypoint_route == route => Some((index, *pos)),
+                _ => None,
+            })
+            .collect();
+        waypoints.sort_unstable_by_key(|(index, pos)| (*index, pos.y, pos.x));
+        waypoints.into_iter().map(|(_, pos)| pos).collect()
+    }
+}
+
+/// Levels compare equal when they hold the same map data, regardless of spritesheet or
+/// pathfinding state.
+impl PartialEq for Level {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.tile_dim == other.tile_dim
+            && self.bounds == other.bounds
+            && self.background_tiles == other.background_tiles
+            && self.foreground_tiles == other.foreground_tiles
+            && self.collision == other.collision
+            && self.entities == other.entities
+            && self.metadata == other.metadata
     }
 }
 
 #[derive(Clone)]
 pub struct LevelBuilder {
     name: String,
-    spritesheet_handle: ImageHandle,
+    spritesheet_handle: Option<ImageHandle>,
+    tile_dim: Option<Vec2>,
     background_tiles: Option<HashMap<Vec2, Vec2>>,
     foreground_tiles: Option<HashMap<Vec2, Vec2>>,
     collision: Option<HashSet<Vec2>>,
-    entities: Option<HashMap<Vec2, String>>,
+    entities: Option<HashMap<Vec2, EntityDef>>,
+    metadata: Option<HashMap<String, String>>,
 }
 
 impl LevelBuilder {
+    pub fn spritesheet_handle(mut self, handle: ImageHandle) -> Self {
+        self.spritesheet_handle = Some(handle);
+        self
+    }
+    /// The tile size to use when the metadata doesn't specify one.
+    pub fn tile_dim(mut self, tile_dim: Vec2) -> Self {
+        self.tile_dim = Some(tile_dim);
+        self
+    }
     pub fn background_tiles(mut self, tiles: HashMap<Vec2, Vec2>) -> Self {
         self.background_tiles = Some(tiles);
         self
@@ -57,39 +222,57 @@ impl LevelBuilder {
         self.collision = Some(collision);
         self
     }
-    pub fn entities(mut self, entities: HashMap<Vec2, String>) -> Self {
+    pub fn entities(mut self, entities: HashMap<Vec2, EntityDef>) -> Self {
         self.entities = Some(entities);
         self
     }
+    pub fn metadata(mut self, metadata: HashMap<String, String>) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
     pub fn build(&self) -> Level {
         if let Some(background_tiles) = self.background_tiles.clone() {
             let foreground_tiles = self.foreground_tiles.clone().unwrap_or_default();
-            let tile_chain = background_tiles.keys().chain(foreground_tiles.keys());
-            let min_x = tile_chain.clone().min_by_key(|v| v.x).unwrap();
-            let min_y = tile_chain.clone().min_by_key(|v| v.y).unwrap();
-            let max_x = tile_chain.clone().max_by_key(|v| v.x).unwrap();
-            let max_y = tile_chain.clone().max_by_key(|v| v.y).unwrap();
-            let width = max_x.x - min_x.x;
-            let height = max_y.y - min_y.y;
-            let dimensions = Vec2::new(width, height);
-            let collision = self.collision.clone().unwrap_or_default();
-            let entities = self.entities.clone().unwrap_or_default();
-            let bfs_flow_field_z = HashMap::from_iter(
-                self.background_tiles
-                    .as_ref()
-                    .unwrap()
+            let bounds =
+                LevelBounds::from_tiles(background_tiles.keys().chain(foreground_tiles.keys()))
+                    .unwrap();
+            let background_tiles: TileGrid<Vec2> = background_tiles.into_iter().collect();
+            let foreground_tiles = foreground_tiles.into_iter().collect();
+            let collision: TileGrid<()> = self
+                .collision
+                .clone()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|pos| (pos, ()))
+                .collect();
+            let flow_field = FlowField::new(
+                background_tiles
                     .keys()
-                    .map(|key| (*key, 0_i16)),
+                    .filter(|pos| !collision.contains(**pos)),
             );
+            let entities = self.entities.clone().unwrap_or_default();
+            let mut metadata = self.metadata.clone().unwrap_or_default();
+            let tile_dim = metadata
+                .remove(TILE_SIZE_KEY)
+                .and_then(|value| parse_tile_size(&value))
+                .or(self.tile_dim)
+                .unwrap_or(DEFAULT_TILE_DIM);
+            let connectivity = metadata
+                .get(CONNECTIVITY_KEY)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(Connectivity::Four);
             Level {
                 name: self.name.clone(),
                 spritesheet_handle: self.spritesheet_handle,
-                dimensions,
+                tile_dim,
+                bounds,
                 background_tiles,
                 foreground_tiles,
                 collision,
                 entities,
-                bfs_flow_field_z,
+                metadata,
+                connectivity,
+                flow_field,
             }
         } else {
             panic!("LevelBuilder requires at least background tiles in order to be constructed");
@@ -129,9 +312,177 @@ pub struct TileMeta {
     pub offset: Vec2F,
 }
 
+/// Ticks of the game simulation per second, unless the manifest says otherwise.
+pub const DEFAULT_TICK_RATE: u32 = 60;
+/// Most ticks run in a single frame. A frame that took longer than this many ticks drops the
+/// rest, so that a slow frame can't make the next one slower still. The dropped time is never
+/// simulated: the game falls behind the clock, and how many ticks a run gets for the same time
+/// played depends on how long its frames took.
+const MAX_TICKS_PER_FRAME: u32 = 8;
+
+/// Runs the game simulation in steps of a fixed `tick`, however long frames take, so that
+/// movement and friction behave the same at any frame rate.
+pub struct FixedTimestep {
+    pub tick: Duration,
+    /// Time passed that hasn't been simulated yet, less than a tick between frames.
+    pub accumulator: Duration,
+}
+
+impl FixedTimestep {
+    pub fn new(tick_rate: u32) -> Self {
+        Self {
+            tick: Duration::from_secs(1) / tick_rate,
+            accumulator: Duration::ZERO,
+        }
+    }
+    /// Adds the time a frame took, returning how many ticks to run for it.
+    pub fn advance(&mut self, elapsed_time: Duration) -> u32 {
+        self.accumulator += elapsed_time;
+        let mut ticks = 0;
+        while self.accumulator >= self.tick && ticks < MAX_TICKS_PER_FRAME {
+            self.accumulator -= self.tick;
+            ticks += 1;
+        }
+        if self.accumulator >= self.tick {
+            self.accumulator = Duration::ZERO;
+        }
+        ticks
+    }
+    /// How far, from 0 to 1, the time not yet simulated is through the next tick, for
+    /// interpolating between the last two ticks.
+    pub fn alpha(&self) -> f32 {
+        self.accumulator.as_secs_f32() / self.tick.as_secs_f32()
+    }
+}
+
+impl Default for FixedTimestep {
+    fn default() -> Self {
+        Self::new(DEFAULT_TICK_RATE)
+    }
+}
+
 pub struct MainMenuResources {
+    pub font_handle: FontHandle,
     pub button_1_handle: ImageHandle,
     pub button_1_bounds: Rect,
     pub button_quit_handle: ImageHandle,
     pub button_quit_bounds: Rect,
 }
+
+pub struct ErrorScreenResources {
+    pub lines: Vec<(ImageHandle, Rect)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounds() -> LevelBounds {
+        let tiles = [Vec2::new(-3, 2), Vec2::new(4, -5), Vec2::new(0, 0)];
+        LevelBounds::from_tiles(tiles.iter()).unwrap()
+    }
+
+    #[test]
+    fn bounds_include_both_extreme_tiles() {
+        let bounds = bounds();
+        assert!(bounds.origin == Vec2::new(-3, -5));
+        assert!(bounds.size == Vec2::new(8, 8));
+        assert!(bounds.max() == Vec2::new(4, 2));
+        let single = LevelBounds::from_tiles([Vec2::new(-1, -1)].iter()).unwrap();
+        assert!(single.size == Vec2::new(1, 1));
+        assert!(LevelBounds::from_tiles(std::iter::empty()).is_none());
+    }
+
+    #[test]
+    fn bounds_contain_their_edges_only() {
+        let bounds = bounds();
+        for tile in [Vec2::new(-3, -5), Vec2::new(4, 2), Vec2::new(-3, 2)] {
+            assert!(bounds.contains(tile));
+        }
+        for tile in [
+            Vec2::new(-4, 0),
+            Vec2::new(5, 0),
+            Vec2::new(0, -6),
+            Vec2::new(0, 3),
+        ] {
+            assert!(!bounds.contains(tile));
+        }
+        assert_eq!(bounds.tiles().count(), 64);
+        assert!(bounds.tiles().all(|tile| bounds.contains(tile)));
+        assert!(bounds.tiles().next() == Some(Vec2::new(-3, -5)));
+    }
+
+    #[test]
+    fn clamps_tiles_into_the_bounds() {
+        let bounds = bounds();
+        assert!(bounds.clamp(Vec2::new(-10, 10)) == Vec2::new(-3, 2));
+        assert!(bounds.clamp(Vec2::new(10, -10)) == Vec2::new(4, -5));
+        assert!(bounds.clamp(Vec2::new(1, 1)) == Vec2::new(1, 1));
+    }
+
+    #[test]
+    fn clamps_views_into_the_bounds() {
+        let bounds = bounds();
+        let view = Vec2F::new(4.0, 2.0);
+        let clamped = bounds.clamp_view(Vec2F::new(-10.0, -10.0), view);
+        assert_eq!((clamped.x, clamped.y), (-3.0, -5.0));
+        // The far edge of the view stops at the far edge of the last tile.
+        let clamped = bounds.clamp_view(Vec2F::new(10.0, 10.0), view);
+        assert_eq!((clamped.x, clamped.y), (1.0, 1.0));
+        let clamped = bounds.clamp_view(Vec2F::new(-1.5, -2.5), view);
+        assert_eq!((clamped.x, clamped.y), (-1.5, -2.5));
+        // A view wider than the bounds is centred on them.
+        let clamped = bounds.clamp_view(Vec2F::new(7.0, 0.0), Vec2F::new(10.0, 2.0));
+        assert_eq!(clamped.x, -4.0);
+    }
+
+    #[test]
+    fn reads_typed_entity_properties() {
+        let background = (0..3).map(|x| (Vec2::new(x, 0), Vec2::new(0, 0))).collect();
+        let entities = [
+            (
+                0,
+                "npc speed=2 aggro=4 patrol=gate facing=left dialogue=greeting",
+            ),
+            (1, "npc"),
+        ]
+        .iter()
+        .map(|(x, label)| (Vec2::new(*x, 0), EntityDef::parse(label).unwrap()))
+        .collect();
+        let level = Level::new("test")
+            .background_tiles(background)
+            .entities(entities)
+            .build();
+        let (set, unset, empty) = (Vec2::new(0, 0), Vec2::new(1, 0), Vec2::new(2, 0));
+        assert_eq!(level.entity_speed(set), Some(2.0));
+        assert_eq!(level.entity_aggro_distance(set), Some(4.0));
+        assert_eq!(level.entity_patrol_route(set), Some("gate"));
+        assert_eq!(level.entity_facing(set), Some(Facing::Left));
+        assert_eq!(level.entity_dialogue_id(set), Some("greeting"));
+        for pos in [unset, empty] {
+            assert_eq!(level.entity_speed(pos), None);
+            assert_eq!(level.entity_facing(pos), None);
+            assert_eq!(level.entity_dialogue_id(pos), None);
+        }
+    }
+
+    #[test]
+    fn runs_whole_ticks_and_keeps_the_remainder() {
+        let mut timestep = FixedTimestep::new(50);
+        assert_eq!(timestep.advance(Duration::from_millis(15)), 0);
+        assert_eq!(timestep.advance(Duration::from_millis(15)), 1);
+        assert_eq!(timestep.accumulator, Duration::from_millis(10));
+        assert!((timestep.alpha() - 0.5).abs() < 1e-6);
+        assert_eq!(timestep.advance(Duration::from_millis(50)), 3);
+        assert_eq!(timestep.accumulator, Duration::ZERO);
+    }
+
+    #[test]
+    fn drops_time_past_the_most_ticks_per_frame() {
+        let mut timestep = FixedTimestep::new(50);
+        let ticks = timestep.advance(Duration::from_secs(1));
+        assert_eq!(ticks, MAX_TICKS_PER_FRAME);
+        assert_eq!(timestep.accumulator, Duration::ZERO);
+        assert_eq!(timestep.advance(Duration::ZERO), 0);
+    }
+}
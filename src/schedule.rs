@@ -0,0 +1,108 @@
+//! The schedule the game simulation runs on, once a tick of the `FixedTimestep`.
+
+use bevy_ecs::event::Events;
+use bevy_ecs::prelude::*;
+
+use crate::ai::*;
+use crate::collision::*;
+use crate::steering::*;
+use crate::systems::*;
+use crate::trigger::*;
+
+/// Inserts the resources the systems of the `fixed_schedule` keep between ticks.
+pub fn insert_simulation_resources(world: &mut World) {
+    world.insert_resource(SpatialHash::default());
+    world.insert_resource(BroadphaseGrid::default());
+    world.insert_resource(Contacts::default());
+    world.insert_resource(Events::<CollisionStarted>::default());
+    world.insert_resource(Events::<CollisionEnded>::default());
+    world.insert_resource(Events::<TriggerEntered>::default());
+    world.insert_resource(Events::<TriggerExited>::default());
+}
+
+/// The schedule of the game simulation. Every two systems of a stage that touch the same
+/// component or resource, with at least one of them writing it, are ordered against each other,
+/// so they run in the same order whether the stage is parallel or, for a deterministic
+/// simulation, single threaded. Paths and velocities are worked out first, then the bodies are
+/// moved, then everything that looks at where they ended up runs, and the camera follows last.
+/// Its systems may run several times a frame with the same input, so they only look at held keys.
+pub fn fixed_schedule(deterministic: bool) -> Schedule {
+    let stage: fn() -> SystemStage = if deterministic {
+        SystemStage::single_threaded
+    } else {
+        SystemStage::parallel
+    };
+    let mut fixed_schedule = Schedule::default();
+    fixed_schedule.add_stage(
+        "store_previous_positions",
+        stage()
+            .with_run_criteria(is_in_game)
+            .with_system(store_previous_positions),
+    );
+    fixed_schedule.add_stage(
+        "update_game",
+        stage()
+            .with_run_criteria(is_in_game)
+            .with_system(update_path_timers)
+            .with_system(handle_player_movement)
+            // The wave starts from where the player ended up on the last tick.
+            .with_system(propagate_pathfinding_wave)
+            .with_system(
+                build_enemy_bfs_paths
+                    .after(update_path_timers)
+                    .after(propagate_pathfinding_wave),
+            )
+            .with_system(handle_path_requests.after(build_enemy_bfs_paths))
+            .with_system(
+                handle_enemy_path_movement
+                    .after(handle_path_requests)
+                    .after(handle_player_movement),
+            )
+            .with_system(handle_enemy_movement_dumb.after(handle_enemy_path_movement))
+            .with_system(update_state_machines.after(handle_enemy_movement_dumb))
+            .with_system(update_patrols.after(update_state_machines))
+            .with_system(update_spatial_hash.after(update_state_machines))
+            .with_system(apply_local_avoidance.after(update_spatial_hash))
+            .with_system(
+                handle_collision
+                    .after(apply_local_avoidance)
+                    .after(update_patrols),
+            )
+            .with_system(
+                update_player_trunc_pos
+                    .after(handle_collision)
+                    .after(propagate_pathfinding_wave),
+            )
+            .with_system(Events::<CollisionStarted>::update_system)
+            .with_system(Events::<CollisionEnded>::update_system)
+            .with_system(
+                detect_collisions
+                    .after(handle_collision)
+                    .after(Events::<CollisionStarted>::update_system)
+                    .after(Events::<CollisionEnded>::update_system),
+            )
+            .with_system(Events::<TriggerEntered>::update_system)
+            .with_system(Events::<TriggerExited>::update_system)
+            .with_system(
+                update_triggers
+                    .after(detect_collisions)
+                    .after(Events::<TriggerEntered>::update_system)
+                    .after(Events::<TriggerExited>::update_system),
+            )
+            .with_system(handle_doors.after(handle_collision))
+            .with_system(
+                handle_player_camera
+                    .after(update_player_trunc_pos)
+                    .after(update_triggers)
+                    .after(handle_doors),
+            )
+            .with_system(get_visible_tiles)
+            .with_system(
+                get_camera_offset
+                    .after(handle_player_camera)
+                    .after(get_visible_tiles),
+            )
+            .with_system(get_tile_offset.after(get_camera_offset)),
+    );
+    fixed_schedule
+}
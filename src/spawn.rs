@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use bevy_ecs::prelude::*;
+use engine::timer::Timer;
+use engine::types::{Vec2, Vec2F};
+
+use crate::ai::{Behaviors, StateMachine};
+use crate::components::*;
+use crate::entity_def::EntityDef;
+use crate::resources::Level;
+use crate::trigger::Trigger;
+
+pub type SpawnFn = fn(&mut World, Vec2F, &EntityDef) -> Entity;
+
+/// Maps level entity kinds to the functions that spawn them.
+pub struct EntityRegistry {
+    factories: HashMap<String, SpawnFn>,
+}
+
+impl EntityRegistry {
+    pub fn empty() -> Self {
+        Self {
+            factories: HashMap::new(),
+        }
+    }
+    pub fn register(&mut self, kind: &str, factory: SpawnFn) {
+        self.factories.insert(kind.to_owned(), factory);
+    }
+    pub fn spawn(&self, world: &mut World, pos: Vec2F, def: &EntityDef) -> Option<Entity> {
+        self.factories
+            .get(def.kind())
+            .map(|factory| factory(world, pos, def))
+    }
+}
+
+impl Default for EntityRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register("player_start", spawn_player);
+        registry.register("enemy_smart", spawn_smart_enemy);
+        registry.register("enemy_dumb", spawn_dumb_enemy);
+        registry.register("npc", spawn_npc);
+        registry.register("spawn", spawn_spawn_point);
+        registry.register("door", spawn_door);
+        registry.register("exit", spawn_door);
+        registry.register("trigger", spawn_trigger);
+        registry
+    }
+}
+
+// `EntityDef`s are validated when they are parsed, so the typed accessors can't fail here.
+
+/// The player is kept between levels, so an existing player is moved instead, taking the
+/// `speed` and size of the new level's `player_start`.
+fn spawn_player(world: &mut World, pos: Vec2F, def: &EntityDef) -> Entity {
+    let speed = Speed::new(def.speed().unwrap().unwrap_or(7.0));
+    let collider = def.collider().unwrap().unwrap_or_default();
+    let mut player_query = world.query_filtered::<Entity, With<Player>>();
+    if let Some(player) = player_query.iter(world).next() {
+        world
+            .entity_mut(player)
+            .insert(Position(pos))
+            .insert(Velocity::default())
+            .insert(speed)
+            .insert(collider);
+        return player;
+    }
+    world
+        .spawn()
+        .insert_bundle(PlayerBundle {
+            position: Position(pos),
+            speed,
+            collider,
+            ..Default::default()
+        })
+        .id()
+}
+
+/// Applies the properties of an enemy on top of the defaults of its bundle. A leash shorter than
+/// the aggro distance is lengthened to match it, and an enemy given only an aggro distance gets
+/// a leash in proportion to it.
+fn apply_enemy_properties(enemy: &mut EnemyBundle, pos: Vec2F, def: &EntityDef) {
+    enemy.position = Position(pos);
+    if let Some(speed) = def.speed().unwrap() {
+        enemy.speed = Speed::new(speed);
+    }
+    if let Some(collider) = def.collider().unwrap() {
+        enemy.collider = collider;
+    }
+    if let Some(aggro_distance) = def.aggro_distance().unwrap() {
+        enemy.aggro_distance = AggroDistance::new(aggro_distance);
+        enemy.leash_distance = LeashDistance::new(aggro_distance * LEASH_FACTOR);
+    }
+    if let Some(leash_distance) = def.leash_distance().unwrap() {
+        enemy.leash_distance = LeashDistance::new(leash_distance);
+    }
+    enemy.leash_distance.0 = enemy.leash_distance.0.max(enemy.aggro_distance.0);
+    if let Some(interval) = def.repath_interval().unwrap() {
+        enemy.path.timer = Timer::new(interval, true);
+    }
+}
+
+/// Inserts the optional components of an enemy. Its patrol route is looked up in the current
+/// `Level`, and ignored with a warning if it has no waypoints.
+fn insert_enemy_options(world: &mut World, enemy: Entity, def: &EntityDef) {
+    let patrol = def.patrol_route().and_then(|route| {
+        let waypoints: Vec<Vec2F> = world
+            .resource::<Level>()
+            .patrol_route(route)
+            .into_iter()
+            .map(|pos| Vec2F::new(pos.x as f32, pos.y as f32))
+            .collect();
+        if waypoints.is_empty() {
+            eprintln!("Patrol route \"{}\" has no waypoints", route);
+            return None;
+        }
+        let mode = def.patrol_mode().unwrap().unwrap_or(PatrolMode::Loop);
+        Some(Patrol::new(waypoints, mode))
+    });
+    let mut enemy = world.entity_mut(enemy);
+    if let Some(connectivity) = def.connectivity().unwrap() {
+        enemy.insert(PathConnectivity(connectivity));
+    }
+    if def.smooth_path().unwrap() == Some(true) {
+        enemy.insert(SmoothPath);
+    }
+    if let Some(facing) = def.facing().unwrap() {
+        enemy.insert(facing);
+    }
+    if let Some(patrol) = patrol {
+        enemy.insert(patrol);
+    }
+}
+
+fn spawn_smart_enemy(world: &mut World, pos: Vec2F, def: &EntityDef) -> Entity {
+    let mut bundle = SmartEnemyBundle::default();
+    apply_enemy_properties(&mut bundle.enemy, pos, def);
+    let enemy = world.spawn().insert_bundle(bundle).id();
+    insert_enemy_options(world, enemy, def);
+    enemy
+}
+
+fn spawn_dumb_enemy(world: &mut World, pos: Vec2F, def: &EntityDef) -> Entity {
+    let mut bundle = DumbEnemyBundle::default();
+    apply_enemy_properties(&mut bundle.enemy, pos, def);
+    let enemy = world.spawn().insert_bundle(bundle).id();
+    insert_enemy_options(world, enemy, def);
+    enemy
+}
+
+/// NPCs driven by a `StateMachine`. One with an unknown `behavior` stands still.
+fn spawn_npc(world: &mut World, pos: Vec2F, def: &EntityDef) -> Entity {
+    let mut bundle = EnemyBundle::default();
+    apply_enemy_properties(&mut bundle, pos, def);
+    let name = def.behavior().unwrap_or_default();
+    let behavior = world
+        .get_resource::<Behaviors>()
+        .and_then(|behaviors| behaviors.get(name))
+        .unwrap_or_else(|| {
+            eprintln!("Unknown behavior \"{}\", the NPC will stand still", name);
+            Arc::default()
+        });
+    let npc = world
+        .spawn()
+        .insert_bundle(bundle)
+        .insert(StateMachine::new(behavior, pos))
+        .id();
+    insert_enemy_options(world, npc, def);
+    if let Some(dialogue) = def.dialogue_id() {
+        world.entity_mut(npc).insert(Dialogue(dialogue.to_owned()));
+    }
+    npc
+}
+
+fn spawn_spawn_point(world: &mut World, pos: Vec2F, def: &EntityDef) -> Entity {
+    world
+        .spawn()
+        .insert_bundle(SpawnPointBundle {
+            spawn_point: SpawnPoint {
+                name: def.name().unwrap_or_default().to_owned(),
+            },
+            position: Position(pos),
+        })
+        .id()
+}
+
+/// Spawns both `door`s and `exit`s, an exit being a door without a target.
+fn spawn_door(world: &mut World, pos: Vec2F, def: &EntityDef) -> Entity {
+    let target = match def.kind() {
+        "door" => def.target_level().map(str::to_owned),
+        _ => None,
+    };
+    world
+        .spawn()
+        .insert_bundle(DoorBundle {
+            door: Door {
+                target,
+                spawn: def.target_spawn().map(str::to_owned),
+            },
+            position: Position(pos),
+        })
+        .id()
+}
+
+/// The named triggers spawned so far by `spawn_level_entities`.
+#[derive(Default)]
+struct TriggerNames(HashMap<String, Entity>);
+
+/// Triggers cover `width` by `height` tiles from where they are placed, one by default. Triggers
+/// sharing a `name` are merged into a single trigger covering all of their tiles.
+fn spawn_trigger(world: &mut World, pos: Vec2F, def: &EntityDef) -> Entity {
+    let origin = Vec2::from(pos);
+    let width = def
+        .width()
+        .unwrap()
+        .map_or(1, |width| width.ceil().max(1.0) as i32);
+    let height = def
+        .height()
+        .unwrap()
+        .map_or(1, |height| height.ceil().max(1.0) as i32);
+    let name = def.name().map(str::to_owned);
+    let existing = name.as_ref().and_then(|name| {
+        world
+            .get_resource::<TriggerNames>()
+            .and_then(|names| names.0.get(name).copied())
+            .filter(|entity| world.get::<Trigger>(*entity).is_some())
+    });
+    let entity = match existing {
+        Some(entity) => entity,
+        None => {
+            let entity = world
+                .spawn()
+                .insert(Trigger::new(name.clone()))
+                .insert(Sensor)
+                .id();
+            if let Some(name) = name {
+                world
+                    .get_resource_or_insert_with(TriggerNames::default)
+                    .0
+                    .insert(name, entity);
+            }
+            entity
+        }
+    };
+    let mut trigger = world.get_mut::<Trigger>(entity).unwrap();
+    trigger.tiles.extend(
+        (0..height).flat_map(|y| (0..width).map(move |x| Vec2::new(origin.x + x, origin.y + y))),
+    );
+    let (position, collider) = trigger.bounding_box();
+    world.entity_mut(entity).insert(position).insert(collider);
+    entity
+}
+
+pub struct UnknownEntity {
+    pub pos: Vec2,
+    pub kind: String,
+}
+
+impl fmt::Display for UnknownEntity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown entity \"{}\" at ({}, {})",
+            self.kind, self.pos.x, self.pos.y
+        )
+    }
+}
+
+/// Spawns every entity of the current `Level` through the `EntityRegistry`, in row order.
+/// Everything but the player is marked as a `LevelEntity`. Entities whose kind isn't registered
+/// are skipped and returned. Patrol waypoints are left to the enemies that walk them.
+pub fn spawn_level_entities(world: &mut World) -> Vec<UnknownEntity> {
+    let mut entities: Vec<(Vec2, EntityDef)> = world
+        .resource::<Level>()
+        .entities
+        .iter()
+        .map(|(pos, def)| (*pos, def.clone()))
+        .collect();
+    entities.sort_unstable_by_key(|(pos, _)| (pos.y, pos.x));
+    world.insert_resource(TriggerNames::default());
+    let unknown = world.resource_scope(|world, registry: Mut<EntityRegistry>| {
+        let mut unknown = Vec::new();
+        for (pos, def) in entities {
+            // Waypoints are only data for the enemies that patrol them.
+            if def.patrol_waypoint().is_some() {
+                continue;
+            }
+            let world_pos = Vec2F::new(pos.x as f32, pos.y as f32);
+            match registry.spawn(world, world_pos, &def) {
+                Some(entity) => {
+                    if !world.entity(entity).contains::<Player>() {
+                        world.entity_mut(entity).insert(LevelEntity);
+                    }
+                }
+                None => unknown.push(UnknownEntity {
+                    pos,
+                    kind: def.kind().to_owned(),
+                }),
+            }
+        }
+        unknown
+    });
+    world.remove_resource::<TriggerNames>();
+    unknown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spawn_label(world: &mut World, label: &str) -> Entity {
+        let def = EntityDef::parse(label).unwrap();
+        EntityRegistry::default()
+            .spawn(world, Vec2F::new(2.0, 3.0), &def)
+            .unwrap()
+    }
+
+    #[test]
+    fn npcs_get_their_facing_and_dialogue() {
+        let mut world = World::new();
+        let npc = spawn_label(&mut world, "npc facing=up dialogue=greeting");
+        assert_eq!(world.get::<Facing>(npc), Some(&Facing::Up));
+        assert_eq!(
+            world
+                .get::<Dialogue>(npc)
+                .map(|dialogue| dialogue.0.as_str()),
+            Some("greeting")
+        );
+        let enemy = spawn_label(&mut world, "enemy_dumb facing=right");
+        assert_eq!(world.get::<Facing>(enemy), Some(&Facing::Right));
+        let plain = spawn_label(&mut world, "npc");
+        assert!(world.get::<Facing>(plain).is_none());
+        assert!(world.get::<Dialogue>(plain).is_none());
+    }
+
+    #[test]
+    fn an_existing_player_takes_the_new_player_start() {
+        let mut world = World::new();
+        let player = spawn_label(&mut world, "player_start speed=3 width=1.5 height=1.5");
+        assert_eq!(world.get::<Speed>(player).unwrap().0, 3.0);
+        let moved = spawn_label(&mut world, "player_start radius=0.25");
+        assert_eq!(moved, player);
+        assert_eq!(world.query::<&Player>().iter(&world).count(), 1);
+        assert_eq!(world.get::<Speed>(player).unwrap().0, 7.0);
+        let (min, max) = world
+            .get::<Collider>(player)
+            .unwrap()
+            .bounds(Vec2F::new(0.0, 0.0));
+        assert!((max.x - min.x - 0.5).abs() < 1e-6);
+    }
+}
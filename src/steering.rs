@@ -0,0 +1,307 @@
+//! Local avoidance between enemies. Enemies following the same path or flow field would
+//! otherwise pile onto the same tile, since walls are the only thing `handle_collision` stops
+//! them against. Each frame the enemies are bucketed into a `SpatialHash`, and each one adjusts
+//! its `Velocity` away from the few neighbours it finds there before it is moved. Bodies are
+//! treated as circles reaching as far as the larger half extent of their `Collider`.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use bevy_ecs::prelude::*;
+use engine::types::{Vec2, Vec2F};
+
+use crate::components::*;
+
+const BATCH_SIZE: usize = 64;
+
+/// Smallest width, in tiles, of a cell of the `SpatialHash`. Cells are widened to fit the
+/// separation distance of the largest bodies, since neighbours are only looked for in the cells
+/// around a body.
+const MIN_CELL_SIZE: f32 = 2.0;
+/// Room, in tiles, kept between the edges of bodies. Bodies closer than the sum of their reaches
+/// and this push each other apart, so default sized bodies keep a tile between their centers.
+const SEPARATION_MARGIN: f32 = 0.2;
+/// How hard, as a fraction of its `Speed`, a body is pushed away from each neighbour it is
+/// right on top of.
+const SEPARATION_WEIGHT: f32 = 1.5;
+/// How far ahead, in seconds, bodies look for neighbours they are about to run into.
+const AVOIDANCE_HORIZON: f32 = 0.5;
+/// How hard, as a fraction of its `Speed`, a body steers away from a neighbour it will pass
+/// right through.
+const AVOIDANCE_WEIGHT: f32 = 1.0;
+
+/// The bodies of the enemies, bucketed by the cell their center is in.
+pub struct SpatialHash {
+    cells: HashMap<Vec2, Vec<Body>>,
+    /// Width of a cell in tiles, at least the largest separation distance between two bodies.
+    cell_size: f32,
+}
+
+impl Default for SpatialHash {
+    fn default() -> Self {
+        Self {
+            cells: HashMap::new(),
+            cell_size: MIN_CELL_SIZE,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Body {
+    pub entity: Entity,
+    /// The center of its `Collider`.
+    pub position: Vec2F,
+    /// In tiles per second.
+    pub velocity: Vec2F,
+    /// How far, in tiles, the body reaches from its center.
+    pub reach: f32,
+}
+
+/// How far a body reaches from its center, the larger half extent of its `Collider`.
+fn reach(collider: &Collider) -> f32 {
+    let half_extents = collider.half_extents();
+    half_extents.x.max(half_extents.y)
+}
+
+/// How close the centers of two bodies can get before they push each other apart.
+fn separation_distance(reach: f32, other_reach: f32) -> f32 {
+    reach + other_reach + SEPARATION_MARGIN
+}
+
+impl SpatialHash {
+    fn cell_of(&self, position: Vec2F) -> Vec2 {
+        Vec2::new(
+            (position.x / self.cell_size).floor() as i32,
+            (position.y / self.cell_size).floor() as i32,
+        )
+    }
+    /// Empties the hash, sizing its cells for bodies reaching up to `largest_reach` tiles.
+    pub fn clear(&mut self, largest_reach: f32) {
+        self.cells.clear();
+        self.cell_size = separation_distance(largest_reach, largest_reach).max(MIN_CELL_SIZE);
+    }
+    pub fn insert(&mut self, body: Body) {
+        self.cells
+            .entry(self.cell_of(body.position))
+            .or_default()
+            .push(body);
+    }
+    /// The bodies in the cell of `position` and the eight around it, which include every body
+    /// close enough to push a body at `position` apart.
+    pub fn neighbours(&self, position: Vec2F) -> impl Iterator<Item = &Body> + '_ {
+        let cell = self.cell_of(position);
+        (-1..=1)
+            .flat_map(move |y| (-1..=1).map(move |x| Vec2::new(cell.x + x, cell.y + y)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+    }
+}
+
+/// Rebuilds the `SpatialHash` from where the enemies are and where they are heading this frame.
+pub fn update_spatial_hash(
+    enemy_query: Query<(Entity, &Position, &Velocity, Option<&Collider>), With<Enemy>>,
+    elapsed_time: Res<Duration>,
+    mut spatial_hash: ResMut<SpatialHash>,
+) {
+    let dt = elapsed_time.as_secs_f32();
+    let bodies: Vec<Body> = enemy_query
+        .iter()
+        .map(|(entity, pos, vel, collider)| {
+            let collider = collider.copied().unwrap_or_default();
+            let velocity = if dt > 0.0 {
+                vel.0 * (1.0 / dt)
+            } else {
+                Vec2F::new(0.0, 0.0)
+            };
+            Body {
+                entity,
+                position: collider.center(pos.0),
+                velocity,
+                reach: reach(&collider),
+            }
+        })
+        .collect();
+    let largest_reach = bodies.iter().map(|body| body.reach).fold(0.0, f32::max);
+    spatial_hash.clear(largest_reach);
+    for body in bodies {
+        spatial_hash.insert(body);
+    }
+}
+
+/// Pushes enemies apart from the neighbours they overlap, and steers them away from the ones
+/// they are about to walk into, keeping them under their `Speed`.
+pub fn apply_local_avoidance(
+    mut enemy_query: Query<
+        (Entity, &Position, &mut Velocity, &Speed, Option<&Collider>),
+        With<Enemy>,
+    >,
+    elapsed_time: Res<Duration>,
+    spatial_hash: Res<SpatialHash>,
+) {
+    let dt = elapsed_time.as_secs_f32();
+    if dt <= 0.0 {
+        return;
+    }
+    let spatial_hash = spatial_hash.as_ref();
+    enemy_query.par_for_each_mut(BATCH_SIZE, |(entity, pos, mut vel, spd, collider)| {
+        let collider = collider.copied().unwrap_or_default();
+        let body = Body {
+            entity,
+            position: collider.center(pos.0),
+            velocity: vel.0 * (1.0 / dt),
+            reach: reach(&collider),
+        };
+        let mut steering = Vec2F::new(0.0, 0.0);
+        for other in spatial_hash.neighbours(body.position) {
+            if other.entity == entity {
+                continue;
+            }
+            steering = steering + separation(&body, other);
+            steering = steering + avoidance(&body, other);
+        }
+        if steering.x == 0.0 && steering.y == 0.0 {
+            return;
+        }
+        let mut velocity = body.velocity + steering * spd.0;
+        if velocity.magnitude() > spd.0 {
+            velocity = velocity.normalize() * spd.0;
+        }
+        vel.0 = velocity * dt;
+    });
+}
+
+/// A push away from `other`, stronger the more the two bodies overlap.
+fn separation(body: &Body, other: &Body) -> Vec2F {
+    let radius = separation_distance(body.reach, other.reach);
+    let offset = body.position - other.position;
+    let distance = offset.magnitude();
+    if distance >= radius {
+        return Vec2F::new(0.0, 0.0);
+    }
+    let direction = if distance > 0.0 {
+        offset * (1.0 / distance)
+    } else if body.entity.id() < other.entity.id() {
+        // Bodies right on top of each other split up the same way every time.
+        Vec2F::new(-1.0, 0.0)
+    } else {
+        Vec2F::new(1.0, 0.0)
+    };
+    direction * ((1.0 - distance / radius) * SEPARATION_WEIGHT)
+}
+
+/// A push away from where `other` will be when the two bodies are closest, if they will overlap
+/// within the `AVOIDANCE_HORIZON`.
+fn avoidance(body: &Body, other: &Body) -> Vec2F {
+    let radius = separation_distance(body.reach, other.reach);
+    let offset = other.position - body.position;
+    let relative_velocity = other.velocity - body.velocity;
+    let speed_squared =
+        relative_velocity.x * relative_velocity.x + relative_velocity.y * relative_velocity.y;
+    if speed_squared == 0.0 {
+        return Vec2F::new(0.0, 0.0);
+    }
+    let time = -(offset.x * relative_velocity.x + offset.y * relative_velocity.y) / speed_squared;
+    if time <= 0.0 || time > AVOIDANCE_HORIZON {
+        return Vec2F::new(0.0, 0.0);
+    }
+    let closest = offset + relative_velocity * time;
+    let distance = closest.magnitude();
+    if distance == 0.0 || distance >= radius {
+        return Vec2F::new(0.0, 0.0);
+    }
+    closest * (-(1.0 - distance / radius) * AVOIDANCE_WEIGHT / distance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_nanos(16_666_667);
+
+    fn spawn_enemy(world: &mut World, x: f32, y: f32, velocity: Vec2F) -> Entity {
+        world
+            .spawn()
+            .insert(Enemy)
+            .insert(Position::new(x, y))
+            .insert(Velocity(velocity))
+            .insert(Speed::new(2.0))
+            .id()
+    }
+
+    fn run_steering(world: &mut World) {
+        world.insert_resource(TICK);
+        world.insert_resource(SpatialHash::default());
+        let mut stage = SystemStage::parallel()
+            .with_system(update_spatial_hash)
+            .with_system(apply_local_avoidance.after(update_spatial_hash));
+        stage.run(world);
+    }
+
+    #[test]
+    fn stacked_enemies_split_up() {
+        let mut world = World::new();
+        let still = Vec2F::new(0.0, 0.0);
+        let a = spawn_enemy(&mut world, 3.0, 3.0, still);
+        let b = spawn_enemy(&mut world, 3.0, 3.0, still);
+        run_steering(&mut world);
+        let (a, b) = (
+            world.get::<Velocity>(a).unwrap().0,
+            world.get::<Velocity>(b).unwrap().0,
+        );
+        assert!(a.x * b.x < 0.0, "{} and {}", a.x, b.x);
+    }
+
+    #[test]
+    fn enemies_out_of_reach_are_left_alone() {
+        let mut world = World::new();
+        let velocity = Vec2F::new(0.01, 0.0);
+        let a = spawn_enemy(&mut world, 0.0, 0.0, velocity);
+        spawn_enemy(&mut world, 0.0, 5.0, velocity);
+        run_steering(&mut world);
+        let a = world.get::<Velocity>(a).unwrap().0;
+        assert_eq!((a.x, a.y), (velocity.x, velocity.y));
+    }
+
+    #[test]
+    fn large_enemies_keep_their_colliders_apart() {
+        let mut world = World::new();
+        let still = Vec2F::new(0.0, 0.0);
+        let a = spawn_enemy(&mut world, 3.0, 3.0, still);
+        let b = spawn_enemy(&mut world, 5.5, 3.0, still);
+        for entity in [a, b] {
+            world.entity_mut(entity).insert(Collider::new_box(3.0, 3.0));
+        }
+        run_steering(&mut world);
+        let (a, b) = (
+            world.get::<Velocity>(a).unwrap().0,
+            world.get::<Velocity>(b).unwrap().0,
+        );
+        assert!(a.x < 0.0 && b.x > 0.0, "{} and {}", a.x, b.x);
+        let spatial_hash = world.resource::<SpatialHash>();
+        let center = Collider::new_box(3.0, 3.0).center(Vec2F::new(3.0, 3.0));
+        assert_eq!(spatial_hash.neighbours(center).count(), 2);
+    }
+
+    /// The stress spawn commented out in `Game::new`: 100 enemies on a diagonal.
+    #[test]
+    fn stress_spawn_only_checks_nearby_enemies() {
+        let mut world = World::new();
+        for i in 5..105 {
+            let toward_origin = Vec2F::new(-1.0, -1.0).normalize() * (2.0 * TICK.as_secs_f32());
+            spawn_enemy(&mut world, i as f32, i as f32, toward_origin);
+        }
+        run_steering(&mut world);
+        let spatial_hash = world.resource::<SpatialHash>();
+        for i in 5..105 {
+            let neighbours = spatial_hash
+                .neighbours(Collider::default().center(Vec2F::new(i as f32, i as f32)))
+                .count();
+            assert!(neighbours <= 6, "{} neighbours at {}", neighbours, i);
+        }
+        let max_step = 2.0 * TICK.as_secs_f32() + 1e-6;
+        for vel in world.query::<&Velocity>().iter(&world) {
+            assert!(vel.0.x.is_finite() && vel.0.y.is_finite());
+            assert!(vel.0.magnitude() <= max_step);
+        }
+    }
+}
@@ -1,11 +1,14 @@
-use std::collections::HashSet;
 use std::time::Duration;
 
 use bevy_ecs::prelude::*;
 use bevy_ecs::schedule::ShouldRun;
 use engine::types::{Vec2, Vec2F, VirtualKeyCode, WinitInputHelper};
 
+use crate::collision::sweep_body;
 use crate::components::*;
+use crate::level_manager::LevelManager;
+use crate::pathfinding::{can_step, find_path, smooth_path};
+use crate::raycast::line_of_sight;
 use crate::resources::*;
 use crate::util::*;
 use crate::{GameRunMode, ShouldQuit};
@@ -26,11 +29,19 @@ pub fn is_in_main_menu(state: Res<GameRunMode>) -> ShouldRun {
     }
 }
 
+pub fn is_in_error_screen(state: Res<GameRunMode>) -> ShouldRun {
+    match state.as_ref() {
+        GameRunMode::Error => ShouldRun::Yes,
+        _ => ShouldRun::No,
+    }
+}
+
 pub fn handle_spacebar(input: Res<WinitInputHelper>, mut state: ResMut<GameRunMode>) {
     if input.key_pressed(VirtualKeyCode::Space) {
         *state = match *state {
             GameRunMode::MainMenu => GameRunMode::Game,
             GameRunMode::Game => GameRunMode::MainMenu,
+            GameRunMode::Error => return,
         }
     }
 }
@@ -54,61 +65,106 @@ pub fn handle_quit_button(
     }
 }
 
+/// Starts a chase once the player is in sight within the `AggroDistance` of an enemy, and only
+/// ends it once they are past its `LeashDistance`. Returns whether the enemy is chasing.
+fn update_aggro(
+    level: &Level,
+    aggroed: &mut Aggroed,
+    pos: Vec2F,
+    player_pos: Vec2F,
+    aggro_distance: &AggroDistance,
+    leash_distance: &LeashDistance,
+) -> bool {
+    aggroed.0 = if aggroed.0 {
+        f32::hypot(player_pos.x - pos.x, player_pos.y - pos.y) <= leash_distance.0
+    } else {
+        line_of_sight(level, pos, player_pos, aggro_distance.0)
+    };
+    aggroed.0
+}
+
 pub fn handle_enemy_movement_dumb(
-    mut enemy_query: Query<(&Enemy, &Dumb, &Position, &mut Velocity, &Speed), Without<Player>>,
+    mut enemy_query: Query<
+        (
+            &Enemy,
+            &Dumb,
+            &Position,
+            &mut Velocity,
+            &Speed,
+            &AggroDistance,
+            &LeashDistance,
+            &mut Aggroed,
+            &mut Path,
+            Option<&Patrol>,
+        ),
+        Without<Player>,
+    >,
     player_query: Query<(&Player, &Position)>,
     elapsed_time: Res<Duration>,
+    level: Res<Level>,
 ) {
+    let level = level.as_ref();
     let (_, player_pos) = player_query.single();
-    enemy_query.par_for_each_mut(BATCH_SIZE, |(_, _, pos, mut vel, spd)| {
-        let distance = f32::sqrt(
-            f32::powi(player_pos.0.x - pos.0.x, 2) + f32::powi(player_pos.0.y - pos.0.y, 2),
-        );
-        if distance < 3.5 && distance > 0.1 {
-            vel.0.x = ((player_pos.0.x - pos.0.x) / distance) * 2.0;
-            vel.0.y = ((player_pos.0.y - pos.0.y) / distance) * 2.0;
-            vel.0 *= elapsed_time.as_secs_f32();
-        } else {
-            vel.0 *= 0.97 * elapsed_time.as_secs_f32();
-        }
-    });
+    enemy_query.par_for_each_mut(
+        BATCH_SIZE,
+        |(
+            _,
+            _,
+            pos,
+            mut vel,
+            spd,
+            aggro_distance,
+            leash_distance,
+            mut aggroed,
+            mut path,
+            patrol,
+        )| {
+            let distance = f32::sqrt(
+                f32::powi(player_pos.0.x - pos.0.x, 2) + f32::powi(player_pos.0.y - pos.0.y, 2),
+            );
+            let chasing = update_aggro(
+                level,
+                &mut aggroed,
+                pos.0,
+                player_pos.0,
+                aggro_distance,
+                leash_distance,
+            );
+            if chasing && distance > 0.1 {
+                vel.0.x = ((player_pos.0.x - pos.0.x) / distance) * spd.0;
+                vel.0.y = ((player_pos.0.y - pos.0.y) / distance) * spd.0;
+                vel.0 *= elapsed_time.as_secs_f32();
+            } else if patrol.is_some() {
+                steer_along_path(pos.0, &mut path, &mut vel, spd.0, *elapsed_time);
+            } else {
+                vel.0 *= 0.97 * elapsed_time.as_secs_f32();
+            }
+        },
+    );
 }
 
+/// Paths around walls are longer than the straight line leashes are measured along, so the wave
+/// spreads this many times further than the largest `LeashDistance`.
+const WAVE_LIMIT_FACTOR: f32 = 2.0;
+
+/// Rebuilds the flow field whenever the player changes tile. The wave only spreads as far as the
+/// `Smart` enemies could chase from, which is always limited: an enemy with an `AggroDistance`
+/// of 0 never starts a chase, rather than chasing from anywhere.
 pub fn propagate_pathfinding_wave(
     player_query: Query<(&Player, &Position), Changed<IntPosition>>,
+    enemy_query: Query<&LeashDistance, With<Smart>>,
     mut level: ResMut<Level>,
 ) {
     if let Ok((_, player_pos)) = player_query.get_single() {
-        level.reset_flow_field();
-        let mut nodes = HashSet::with_capacity(level.background_tiles.len());
-        nodes.insert((Vec2::from(player_pos.0), 1));
-        while !nodes.is_empty() {
-            let mut new_nodes = HashSet::with_capacity(4); // TODO expensive, reallocates each loop...
-            for node in nodes.iter() {
-                // discovery
-                let mut pos = node.0;
-                let distance = node.1;
-                level.bfs_flow_field_z.insert(pos, distance);
-                pos.offset_x(1);
-                if *level.bfs_flow_field_z.get(&pos).unwrap_or(&-1) == 0 {
-                    new_nodes.insert((pos, distance + 1));
-                }
-                pos.offset_x(-2);
-                if *level.bfs_flow_field_z.get(&pos).unwrap_or(&-1) == 0 {
-                    new_nodes.insert((pos, distance + 1));
-                }
-                pos.offset_x(1);
-                pos.offset_y(1);
-                if *level.bfs_flow_field_z.get(&pos).unwrap_or(&-1) == 0 {
-                    new_nodes.insert((pos, distance + 1));
-                }
-                pos.offset_y(-2);
-                if *level.bfs_flow_field_z.get(&pos).unwrap_or(&-1) == 0 {
-                    new_nodes.insert((pos, distance + 1));
-                }
-            }
-            nodes = new_nodes;
-        }
+        let leash_distance = enemy_query
+            .iter()
+            .map(|leash_distance| leash_distance.0)
+            .fold(0.0, f32::max);
+        let limit = Some((leash_distance * WAVE_LIMIT_FACTOR).ceil() as u32);
+        let connectivity = level.connectivity;
+        level
+            .flow_field
+            .propagate(tile_of(player_pos.0), limit, connectivity);
     }
 }
 
@@ -118,94 +174,214 @@ pub fn update_path_timers(mut path_query: Query<&mut Path>, elapsed_time: Res<Du
     });
 }
 
+/// Walks each `Smart` enemy down the flow field to the player. Only steps that lower the cost
+/// are taken, so an enemy that can't follow the field, such as a four-connected enemy on an
+/// eight-connected level, stops short instead of wandering. Patrolling enemies are left to
+/// their route, and get a path as soon as they leave it.
 pub fn build_enemy_bfs_paths(
-    mut enemy_query: Query<(&Enemy, &Smart, &Position, &mut Path), Without<Player>>,
+    mut enemy_query: Query<
+        (
+            &Enemy,
+            &Smart,
+            &Position,
+            &mut Path,
+            Option<&PathConnectivity>,
+            Option<&SmoothPath>,
+            &Aggroed,
+            Option<&Patrol>,
+            &Collider,
+        ),
+        Without<Player>,
+    >,
     player_query: Query<(&Player, &Position, &IntPosition)>,
     level: Res<Level>,
 ) {
     let level = level.as_ref();
+    let flow_field = &level.flow_field;
     if let Ok((_, player_pos, player_int_pos)) = player_query.get_single() {
-        enemy_query.par_for_each_mut(BATCH_SIZE, |(_, _, pos, mut path_export)| {
-            if !path_export.timer.done {
-                return;
-            }
-            let mut path = vec![Vec2F::from(pos.0)];
-            let mut pos_x = pos.0.x as i32;
-            let mut pos_y = pos.0.y as i32;
-            let mut no_path = false;
-            while !(pos_x == player_int_pos.0.x && pos_y == player_int_pos.0.y) && !no_path {
-                let mut neighbors = Vec::with_capacity(4);
-                if let Some(distance) = level.bfs_flow_field_z.get(&Vec2::new(pos_x, pos_y - 1)) {
-                    if distance > &0 {
-                        neighbors.push((pos_x, pos_y - 1, distance));
-                    }
+        enemy_query.par_for_each_mut(
+            BATCH_SIZE,
+            |(_, _, pos, mut path_export, path_connectivity, smooth, aggroed, patrol, collider)| {
+                if patrol.is_some() && !aggroed.0 {
+                    return;
                 }
-                if let Some(distance) = level.bfs_flow_field_z.get(&Vec2::new(pos_x + 1, pos_y)) {
-                    if distance > &0 {
-                        neighbors.push((pos_x + 1, pos_y, distance));
-                    }
+                // `update_patrols` drops the path of an enemy leaving its route, so that it starts
+                // chasing straight away instead of waiting for the timer.
+                let left_patrol = patrol.is_some() && path_export.next_point.is_none();
+                if !path_export.timer.done && !left_patrol {
+                    return;
                 }
-                if let Some(distance) = level.bfs_flow_field_z.get(&Vec2::new(pos_x, pos_y + 1)) {
-                    if distance > &0 {
-                        neighbors.push((pos_x, pos_y + 1, distance));
+                let connectivity = path_connectivity.map_or(level.connectivity, |c| c.0);
+                let footprint = collider.footprint();
+                let fits = |tile| footprint.fits(tile, |tile| flow_field.is_walkable(tile));
+                let mut path = vec![Vec2F::from(pos.0)];
+                let mut tile = tile_of(pos.0);
+                let mut distance = flow_field.distance(tile).unwrap_or(u32::MAX);
+                while tile != player_int_pos.0 {
+                    let next = connectivity
+                        .steps()
+                        .iter()
+                        .filter(|(offset, _)| can_step(tile, *offset, fits))
+                        .filter_map(|(offset, cost)| {
+                            let next = Vec2::new(tile.x + offset.x, tile.y + offset.y);
+                            flow_field
+                                .distance(next)
+                                .filter(|next_distance| *next_distance < distance)
+                                .map(|next_distance| (next, next_distance, next_distance + cost))
+                        })
+                        .min_by_key(|(_, _, total)| *total);
+                    match next {
+                        Some((next, next_distance, _)) => {
+                            tile = next;
+                            distance = next_distance;
+                            path.push(Vec2F::new(tile.x as f32, tile.y as f32));
+                        }
+                        None => break,
                     }
                 }
-                if let Some(distance) = level.bfs_flow_field_z.get(&Vec2::new(pos_x - 1, pos_y)) {
-                    if distance > &0 {
-                        neighbors.push((pos_x - 1, pos_y, distance));
-                    }
+                // An enemy that couldn't get all the way down the field stops where it got to.
+                if tile == player_int_pos.0 {
+                    path.push(player_pos.0);
                 }
-                neighbors.sort_unstable_by_key(|item| item.2);
-                if neighbors.is_empty() {
-                    no_path = true;
-                } else {
-                    let next_point = neighbors[0];
-                    pos_x = next_point.0;
-                    pos_y = next_point.1;
-                    path.push(Vec2F::new(pos_x as f32, pos_y as f32));
+                if smooth.is_some() {
+                    path = smooth_path(level, collider, &path);
+                }
+                path_export.points = path;
+                path_export.next_point = path_export.points.get(0).map(|v| *v);
+                path_export.timer.restart();
+            },
+        );
+    }
+}
+
+/// Fills the `Path` of entities with a `PathRequest`, leaving it empty when the target can't be
+/// reached, and removes the request.
+pub fn handle_path_requests(
+    mut commands: Commands,
+    mut query: Query<(
+        Entity,
+        &Position,
+        &PathRequest,
+        &mut Path,
+        Option<&PathConnectivity>,
+        Option<&SmoothPath>,
+        Option<&Collider>,
+    )>,
+    level: Res<Level>,
+) {
+    for (entity, pos, request, mut path, path_connectivity, smooth, collider) in query.iter_mut() {
+        let start = tile_of(pos.0);
+        let goal = tile_of(request.target);
+        let connectivity = path_connectivity.map_or(level.connectivity, |c| c.0);
+        let collider = collider.copied().unwrap_or_default();
+        path.points = match find_path(
+            &level,
+            start,
+            goal,
+            request.heuristic,
+            connectivity,
+            collider.footprint(),
+        ) {
+            Some(tiles) => {
+                let mut points = vec![pos.0];
+                points.extend(
+                    tiles
+                        .iter()
+                        .skip(1)
+                        .map(|tile| Vec2F::new(tile.x as f32, tile.y as f32)),
+                );
+                points.push(request.target);
+                if smooth.is_some() {
+                    points = smooth_path(&level, &collider, &points);
                 }
+                points
             }
-            path.push(player_pos.0);
-            path_export.points = path;
-            path_export.next_point = path_export.points.get(0).map(|v| *v);
-            path_export.timer.restart();
-        });
+            None => Vec::new(),
+        };
+        path.next_point = path.points.first().copied();
+        commands.entity(entity).remove::<PathRequest>();
+    }
+}
+
+/// Steers towards the next point of a `Path`, moving on to the following point once it's
+/// reached.
+pub fn steer_along_path(
+    pos: Vec2F,
+    path: &mut Path,
+    vel: &mut Velocity,
+    speed: f32,
+    elapsed_time: Duration,
+) {
+    if let Some(point) = path.next_point {
+        if f32::abs(pos.x - point.x as f32) < 0.01
+            && f32::abs(pos.y - point.y as f32) < 0.01
+            && path.points.len() > 0
+        {
+            path.next_point = Some(path.points.remove(0));
+        }
+    }
+    if let Some(point) = path.next_point {
+        let point_distance =
+            f32::sqrt(f32::powi(point.x as f32 - pos.x, 2) + f32::powi(point.y as f32 - pos.y, 2));
+        if point_distance != 0.0 {
+            vel.0.x = ((point.x as f32 - pos.x) / point_distance) * speed;
+            vel.0.y = ((point.y as f32 - pos.y) / point_distance) * speed;
+            vel.0 *= elapsed_time.as_secs_f32();
+        }
     }
 }
 
 pub fn handle_enemy_path_movement(
-    mut enemy_query: Query<(&Enemy, &Smart, &Position, &mut Velocity, &Speed, &mut Path)>,
+    mut enemy_query: Query<(
+        &Enemy,
+        &Smart,
+        &Position,
+        &mut Velocity,
+        &Speed,
+        &mut Path,
+        &AggroDistance,
+        &LeashDistance,
+        &mut Aggroed,
+        Option<&Patrol>,
+    )>,
     player_query: Query<(&Player, &Position)>,
     elapsed_time: Res<Duration>,
+    level: Res<Level>,
 ) {
+    let level = level.as_ref();
     let (_, player_pos) = player_query.single();
-    enemy_query.par_for_each_mut(BATCH_SIZE, |(_, _, pos, mut vel, spd, mut path)| {
-        let distance = f32::sqrt(
-            f32::powi(player_pos.0.x - pos.0.x, 2) + f32::powi(player_pos.0.y - pos.0.y, 2),
-        );
-        if distance < 10.5 && distance > 0.1 {
-            if let Some(point) = path.next_point {
-                if f32::abs(pos.0.x - point.x as f32) < 0.01
-                    && f32::abs(pos.0.y - point.y as f32) < 0.01
-                    && path.points.len() > 0
-                {
-                    path.next_point = Some(path.points.remove(0));
-                }
-            }
-            if let Some(point) = path.next_point {
-                let point_distance = f32::sqrt(
-                    f32::powi(point.x as f32 - pos.0.x, 2) + f32::powi(point.y as f32 - pos.0.y, 2),
-                );
-                if point_distance != 0.0 {
-                    vel.0.x = ((point.x as f32 - pos.0.x) / point_distance) * 2.0;
-                    vel.0.y = ((point.y as f32 - pos.0.y) / point_distance) * 2.0;
-                    vel.0 *= elapsed_time.as_secs_f32();
-                }
+    enemy_query.par_for_each_mut(
+        BATCH_SIZE,
+        |(
+            _,
+            _,
+            pos,
+            mut vel,
+            spd,
+            mut path,
+            aggro_distance,
+            leash_distance,
+            mut aggroed,
+            patrol,
+        )| {
+            let distance = f32::sqrt(
+                f32::powi(player_pos.0.x - pos.0.x, 2) + f32::powi(player_pos.0.y - pos.0.y, 2),
+            );
+            let chasing = update_aggro(
+                level,
+                &mut aggroed,
+                pos.0,
+                player_pos.0,
+                aggro_distance,
+                leash_distance,
+            );
+            if (chasing && distance > 0.1) || patrol.is_some() {
+                steer_along_path(pos.0, &mut path, &mut vel, spd.0, *elapsed_time);
+            } else {
+                vel.0 *= 0.97 * elapsed_time.as_secs_f32();
             }
-        } else {
-            vel.0 *= 0.97 * elapsed_time.as_secs_f32();
-        }
-    });
+        },
+    );
 }
 
 pub fn handle_player_movement(
@@ -246,107 +422,55 @@ pub fn handle_player_movement(
     }
 }
 
-pub fn handle_collision(mut query: Query<(&mut Position, &mut Velocity)>, level: Res<Level>) {
+/// Moves bodies by their `Velocity`, stopping their `Collider` against walls.
+pub fn handle_collision(
+    mut query: Query<(&mut Position, &mut Velocity, &Collider)>,
+    level: Res<Level>,
+) {
     let level = level.as_ref();
-    query.par_for_each_mut(BATCH_SIZE, |(mut pos, mut vel)| {
-        let mut new_position = pos.0 + vel.0;
-        let leeway = 1.0_f32;
-        let border = 0.1_f32;
-
-        // Collision handling
-        if vel.0.x <= 0.0 {
-            // moving left
-            if level
-                .collision
-                .get(&Vec2::new(
-                    (new_position.x + border).floor() as i32,
-                    (pos.0.y + border).floor() as i32,
-                ))
-                .is_some()
-                || level
-                    .collision
-                    .get(&Vec2::new(
-                        (new_position.x + border).floor() as i32,
-                        (pos.0.y + leeway - border).floor() as i32,
-                    ))
-                    .is_some()
-            {
-                new_position.x = new_position.x.floor() + 1.0;
-                vel.0.x = 0.0;
-            }
-        } else {
-            // moving right
-            if level
-                .collision
-                .get(&Vec2::new(
-                    (new_position.x + 1.0 - border).floor() as i32,
-                    (pos.0.y + border).floor() as i32,
-                ))
-                .is_some()
-                || level
-                    .collision
-                    .get(&Vec2::new(
-                        (new_position.x + 1.0 - border).floor() as i32,
-                        (pos.0.y + leeway - border).floor() as i32,
-                    ))
-                    .is_some()
-            {
-                new_position.x = new_position.x.floor();
-                vel.0.x = 0.0;
-            }
+    query.par_for_each_mut(BATCH_SIZE, |(mut pos, mut vel, collider)| {
+        let sweep = sweep_body(level, collider, pos.0, vel.0);
+        if sweep.blocked_x {
+            vel.0.x = 0.0;
         }
-        if vel.0.y <= 0.0 {
-            // moving up
-            if level
-                .collision
-                .get(&Vec2::new(
-                    (new_position.x + border).floor() as i32,
-                    (new_position.y + border).floor() as i32,
-                ))
-                .is_some()
-                || level
-                    .collision
-                    .get(&Vec2::new(
-                        (new_position.x + leeway - border).floor() as i32,
-                        (new_position.y + border).floor() as i32,
-                    ))
-                    .is_some()
-            {
-                new_position.y = new_position.y.floor() + 1.0;
-                vel.0.y = 0.0;
-            }
-        } else {
-            // moving down
-            if level
-                .collision
-                .get(&Vec2::new(
-                    (new_position.x + border).floor() as i32,
-                    (new_position.y + 1.0 - border).floor() as i32,
-                ))
-                .is_some()
-                || level
-                    .collision
-                    .get(&Vec2::new(
-                        (new_position.x + leeway - border).floor() as i32,
-                        (new_position.y + 1.0 - border).floor() as i32,
-                    ))
-                    .is_some()
-            {
-                new_position.y = new_position.y.floor();
-                vel.0.y = 0.0;
-            }
+        if sweep.blocked_y {
+            vel.0.y = 0.0;
         }
-        pos.0 = new_position;
+        pos.0 = sweep.position;
     });
 }
 
+pub fn handle_doors(
+    player_query: Query<(&Player, &Position)>,
+    door_query: Query<(&Door, &Position)>,
+    mut level_manager: ResMut<LevelManager>,
+) {
+    let (_, player_pos) = player_query.single();
+    let player_tile = Vec2::new(
+        (player_pos.0.x + 0.5).floor() as i32,
+        (player_pos.0.y + 0.5).floor() as i32,
+    );
+    for (door, door_pos) in door_query.iter() {
+        if tile_of(door_pos.0) != player_tile {
+            continue;
+        }
+        let level = match &door.target {
+            Some(id) => level_manager.index_of(id),
+            None => Some(level_manager.next()),
+        };
+        if let Some(level) = level {
+            level_manager.request(level, door.spawn.clone());
+        }
+    }
+}
+
 pub fn update_player_trunc_pos(
     mut player_query: Query<(&Player, &Position, &mut IntPosition), Changed<Position>>,
 ) {
     if let Ok((_, pos, mut int_pos)) = player_query.get_single_mut() {
-        if int_pos.0 != Vec2::from(pos.0) {
-            int_pos.0.x = pos.0.x as i32;
-            int_pos.0.y = pos.0.y as i32;
+        let tile = tile_of(pos.0);
+        if int_pos.0 != tile {
+            int_pos.0 = tile;
         }
     }
 }
@@ -365,27 +489,59 @@ pub fn get_visible_tiles(screen: Res<Screen>, mut tile_meta: ResMut<TileMeta>) {
     tile_meta.visible.y = screen.dim.y / tile_meta.dim.y;
 }
 
-pub fn get_camera_offset(mut query: Query<(&mut Camera, &mut Position)>, tile_meta: Res<TileMeta>) {
+pub fn get_camera_offset(
+    mut query: Query<(&mut Camera, &mut Position)>,
+    tile_meta: Res<TileMeta>,
+    screen: Res<Screen>,
+    level: Res<Level>,
+) {
     let (mut camera, pos) = query.single_mut();
-    camera.offset.x = pos.0.x - tile_meta.visible.x as f32 / 2.0;
-    camera.offset.y = pos.0.y - tile_meta.visible.y as f32 / 2.0;
-
-    /*
-    if offset_x < 0.0 {
-        offset_x = 0.0;
-    } else if offset_x > (level.dimensions.x - visible_tiles.x) as f32 {
-        offset_x = (level.dimensions.x - visible_tiles.x) as f32;
-    }
-    if offset_y < 0.0 {
-        offset_y = 0.0;
-    } else if offset_y > (level.dimensions.y - visible_tiles.y) as f32 {
-        offset_y = (level.dimensions.y - visible_tiles.y) as f32;
-    }
-    */
+    let offset = Vec2F::new(
+        pos.0.x - tile_meta.visible.x as f32 / 2.0,
+        pos.0.y - tile_meta.visible.y as f32 / 2.0,
+    );
+    let view = Vec2F::new(
+        screen.dim.x as f32 / tile_meta.dim.x as f32,
+        screen.dim.y as f32 / tile_meta.dim.y as f32,
+    );
+    camera.offset = level.bounds.clamp_view(offset, view);
 }
 
 pub fn get_tile_offset(query: Query<&Camera>, mut tile_meta: ResMut<TileMeta>) {
     let camera = query.single();
-    tile_meta.offset.x = (camera.offset.x - camera.offset.x.trunc()) * tile_meta.dim.x as f32;
-    tile_meta.offset.y = (camera.offset.y - camera.offset.y.trunc()) * tile_meta.dim.y as f32;
+    tile_meta.offset = tile_offset(camera.offset, tile_meta.dim);
+}
+
+/// How far, in pixels, the tiles are scrolled past the tile grid for a camera offset.
+pub fn tile_offset(camera_offset: Vec2F, tile_dim: Vec2) -> Vec2F {
+    Vec2F::new(
+        (camera_offset.x - camera_offset.x.trunc()) * tile_dim.x as f32,
+        (camera_offset.y - camera_offset.y.trunc()) * tile_dim.y as f32,
+    )
+}
+
+/// Keeps where bodies and the camera were before the tick, for drawing between ticks.
+pub fn store_previous_positions(
+    mut query: Query<(&Position, &mut PreviousPosition)>,
+    mut camera_query: Query<&mut Camera>,
+) {
+    query.par_for_each_mut(BATCH_SIZE, |(pos, mut previous_pos)| {
+        previous_pos.0 = pos.0;
+    });
+    for mut camera in camera_query.iter_mut() {
+        camera.previous_offset = camera.offset;
+    }
+}
+
+/// Stops bodies that were placed rather than moved, such as when a level is loaded, from being
+/// drawn sliding over from where they were.
+pub fn reset_interpolation(world: &mut World) {
+    let mut query = world.query::<(&Position, &mut PreviousPosition)>();
+    for (pos, mut previous_pos) in query.iter_mut(world) {
+        previous_pos.0 = pos.0;
+    }
+    let mut camera_query = world.query::<&mut Camera>();
+    for mut camera in camera_query.iter_mut(world) {
+        camera.previous_offset = camera.offset;
+    }
 }
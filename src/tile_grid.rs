@@ -0,0 +1,521 @@
+use std::collections::HashMap;
+
+use engine::types::Vec2;
+
+use crate::resources::LevelBounds;
+
+const CHUNK_SIZE: i32 = 16;
+const CHUNK_AREA: usize = (CHUNK_SIZE * CHUNK_SIZE) as usize;
+/// Grids covering more than this many tiles per stored tile fall back to a `HashMap`.
+const MAX_DENSE_TILES_PER_ENTRY: i64 = 64;
+/// Below this area a dense grid is always cheap enough.
+const MIN_SPARSE_AREA: i64 = 4096;
+
+type Chunk<T> = Box<[Option<T>]>;
+
+enum Storage<T> {
+    /// Chunks of `CHUNK_SIZE` squared tiles, allocated when first written to.
+    Dense {
+        bounds: LevelBounds,
+        chunks_wide: i32,
+        chunks: Vec<Option<Chunk<T>>>,
+    },
+    Sparse(HashMap<Vec2, T>),
+}
+
+/// A map from tile positions to values, stored as a dense chunked grid over the bounds of its
+/// contents, or as a `HashMap` when those bounds are mostly empty.
+pub struct TileGrid<T> {
+    storage: Storage<T>,
+    len: usize,
+}
+
+impl<T> TileGrid<T> {
+    pub fn new() -> Self {
+        Self {
+            storage: Storage::Sparse(HashMap::new()),
+            len: 0,
+        }
+    }
+    pub fn with_bounds(bounds: LevelBounds) -> Self {
+        let chunks_wide = (bounds.size.x + CHUNK_SIZE - 1) / CHUNK_SIZE;
+        let chunks_high = (bounds.size.y + CHUNK_SIZE - 1) / CHUNK_SIZE;
+        let mut chunks = Vec::new();
+        chunks.resize_with((chunks_wide * chunks_high) as usize, || None);
+        Self {
+            storage: Storage::Dense {
+                bounds,
+                chunks_wide,
+                chunks,
+            },
+            len: 0,
+        }
+    }
+    pub fn is_dense(&self) -> bool {
+        matches!(self.storage, Storage::Dense { .. })
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn get(&self, pos: Vec2) -> Option<&T> {
+        match &self.storage {
+            Storage::Dense {
+                bounds,
+                chunks_wide,
+                chunks,
+            } => {
+                let (chunk, index) = locate(bounds, *chunks_wide, pos)?;
+                chunks[chunk].as_ref()?[index].as_ref()
+            }
+            Storage::Sparse(map) => map.get(&pos),
+        }
+    }
+    pub fn get_mut(&mut self, pos: Vec2) -> Option<&mut T> {
+        match &mut self.storage {
+            Storage::Dense {
+                bounds,
+                chunks_wide,
+                chunks,
+            } => {
+                let (chunk, index) = locate(bounds, *chunks_wide, pos)?;
+                chunks[chunk].as_mut()?[index].as_mut()
+            }
+            Storage::Sparse(map) => map.get_mut(&pos),
+        }
+    }
+    pub fn contains(&self, pos: Vec2) -> bool {
+        self.get(pos).is_some()
+    }
+    pub fn insert(&mut self, pos: Vec2, value: T) -> Option<T> {
+        if let Storage::Dense { bounds, .. } = &self.storage {
+            if !bounds.contains(pos) {
+                self.grow(pos);
+            }
+        }
+        let previous = match &mut self.storage {
+            Storage::Dense {
+                bounds,
+                chunks_wide,
+                chunks,
+            } => {
+                let (chunk, index) = locate(bounds, *chunks_wide, pos).unwrap();
+                let chunk = chunks[chunk].get_or_insert_with(|| {
+                    let mut chunk = Vec::with_capacity(CHUNK_AREA);
+                    chunk.resize_with(CHUNK_AREA, || None);
+                    chunk.into_boxed_slice()
+                });
+                chunk[index].replace(value)
+            }
+            Storage::Sparse(map) => map.insert(pos, value),
+        };
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+    pub fn remove(&mut self, pos: Vec2) -> Option<T> {
+        let removed = match &mut self.storage {
+            Storage::Dense {
+                bounds,
+                chunks_wide,
+                chunks,
+            } => {
+                let (chunk, index) = locate(bounds, *chunks_wide, pos)?;
+                chunks[chunk].as_mut()?[index].take()
+            }
+            Storage::Sparse(map) => map.remove(&pos),
+        };
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+    /// Grows the bounds of a dense grid to include `pos`. The bounds grow by whole chunks, and at
+    /// least double along each side they grow past, so that filling a grid from one edge only
+    /// regrows it a few times. The existing chunks are moved over without touching their tiles.
+    /// If the tiles would mostly be empty space, the grid switches to a `HashMap` instead.
+    fn grow(&mut self, pos: Vec2) {
+        let (bounds, chunks_wide, chunks) = match &mut self.storage {
+            Storage::Dense {
+                bounds,
+                chunks_wide,
+                chunks,
+            } => (bounds, chunks_wide, chunks),
+            Storage::Sparse(_) => return,
+        };
+        let tight = LevelBounds::from_tiles([bounds.origin, bounds.max(), pos].iter()).unwrap();
+        let area = tight.size.x as i64 * tight.size.y as i64;
+        if area > MIN_SPARSE_AREA && area > (self.len as i64 + 1) * MAX_DENSE_TILES_PER_ENTRY {
+            let len = self.len;
+            let entries = std::mem::take(self).into_entries();
+            self.storage = Storage::Sparse(entries.into_iter().collect());
+            self.len = len;
+            return;
+        }
+        let old_wide = *chunks_wide;
+        let old_high = if old_wide > 0 {
+            chunks.len() as i32 / old_wide
+        } else {
+            0
+        };
+        let chunk_x = (pos.x - bounds.origin.x).div_euclid(CHUNK_SIZE);
+        let chunk_y = (pos.y - bounds.origin.y).div_euclid(CHUNK_SIZE);
+        // How many chunks to add before and after the existing ones along an axis.
+        let slack = |chunk: i32, old: i32| {
+            let step = old.max(1);
+            if chunk < 0 {
+                ((-chunk).max(step), 0)
+            } else if chunk >= old {
+                (0, (chunk - old + 1).max(step))
+            } else {
+                (0, 0)
+            }
+        };
+        let (left, right) = slack(chunk_x, old_wide);
+        let (top, bottom) = slack(chunk_y, old_high);
+        let new_wide = old_wide + left + right;
+        let new_high = old_high + top + bottom;
+        let mut new_chunks = Vec::new();
+        new_chunks.resize_with((new_wide * new_high) as usize, || None);
+        for (index, chunk) in std::mem::take(chunks).into_iter().enumerate() {
+            let (x, y) = (
+                index as i32 % old_wide + left,
+                index as i32 / old_wide + top,
+            );
+            new_chunks[(y * new_wide + x) as usize] = chunk;
+        }
+        *bounds = LevelBounds {
+            origin: Vec2::new(
+                bounds.origin.x - left * CHUNK_SIZE,
+                bounds.origin.y - top * CHUNK_SIZE,
+            ),
+            size: Vec2::new(new_wide * CHUNK_SIZE, new_high * CHUNK_SIZE),
+        };
+        *chunks_wide = new_wide;
+        *chunks = new_chunks;
+    }
+    fn with_storage_for(bounds: LevelBounds, len: usize) -> Self {
+        let area = bounds.size.x as i64 * bounds.size.y as i64;
+        if area > MIN_SPARSE_AREA && area > len as i64 * MAX_DENSE_TILES_PER_ENTRY {
+            Self {
+                storage: Storage::Sparse(HashMap::with_capacity(len)),
+                len: 0,
+            }
+        } else {
+            Self::with_bounds(bounds)
+        }
+    }
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (Vec2, &T)> + '_> {
+        match &self.storage {
+            Storage::Dense {
+                bounds,
+                chunks_wide,
+                chunks,
+            } => {
+                let (bounds, chunks_wide) = (*bounds, *chunks_wide);
+                Box::new(
+                    chunks
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(chunk_index, chunk)| {
+                            chunk.as_ref().map(|chunk| (chunk_index, chunk))
+                        })
+                        .flat_map(move |(chunk_index, chunk)| {
+                            chunk.iter().enumerate().filter_map(move |(index, value)| {
+                                value.as_ref().map(|value| {
+                                    (position(&bounds, chunks_wide, chunk_index, index), value)
+                                })
+                            })
+                        }),
+                )
+            }
+            Storage::Sparse(map) => Box::new(map.iter().map(|(pos, value)| (*pos, value))),
+        }
+    }
+    pub fn iter_mut(&mut self) -> Box<dyn Iterator<Item = (Vec2, &mut T)> + '_> {
+        match &mut self.storage {
+            Storage::Dense {
+                bounds,
+                chunks_wide,
+                chunks,
+            } => {
+                let (bounds, chunks_wide) = (*bounds, *chunks_wide);
+                Box::new(
+                    chunks
+                        .iter_mut()
+                        .enumerate()
+                        .filter_map(|(chunk_index, chunk)| {
+                            chunk.as_mut().map(|chunk| (chunk_index, chunk))
+                        })
+                        .flat_map(move |(chunk_index, chunk)| {
+                            chunk
+                                .iter_mut()
+                                .enumerate()
+                                .filter_map(move |(index, value)| {
+                                    value.as_mut().map(|value| {
+                                        (position(&bounds, chunks_wide, chunk_index, index), value)
+                                    })
+                                })
+                        }),
+                )
+            }
+            Storage::Sparse(map) => Box::new(map.iter_mut().map(|(pos, value)| (*pos, value))),
+        }
+    }
+    pub fn keys(&self) -> impl Iterator<Item = Vec2> + '_ {
+        self.iter().map(|(pos, _)| pos)
+    }
+    fn into_entries(self) -> Vec<(Vec2, T)> {
+        match self.storage {
+            Storage::Dense {
+                bounds,
+                chunks_wide,
+                chunks,
+            } => {
+                let mut entries = Vec::with_capacity(self.len);
+                for (chunk_index, chunk) in chunks.into_iter().enumerate() {
+                    let chunk = match chunk {
+                        Some(chunk) => chunk,
+                        None => continue,
+                    };
+                    for (index, value) in chunk.into_vec().into_iter().enumerate() {
+                        if let Some(value) = value {
+                            let pos = position(&bounds, chunks_wide, chunk_index, index);
+                            entries.push((pos, value));
+                        }
+                    }
+                }
+                entries
+            }
+            Storage::Sparse(map) => map.into_iter().collect(),
+        }
+    }
+}
+
+/// The chunk and the index within it of a tile, if it is inside the bounds.
+fn locate(bounds: &LevelBounds, chunks_wide: i32, pos: Vec2) -> Option<(usize, usize)> {
+    if !bounds.contains(pos) {
+        return None;
+    }
+    let x = pos.x - bounds.origin.x;
+    let y = pos.y - bounds.origin.y;
+    let chunk = (y / CHUNK_SIZE) * chunks_wide + x / CHUNK_SIZE;
+    let index = (y % CHUNK_SIZE) * CHUNK_SIZE + x % CHUNK_SIZE;
+    Some((chunk as usize, index as usize))
+}
+
+/// The inverse of `locate`.
+fn position(bounds: &LevelBounds, chunks_wide: i32, chunk: usize, index: usize) -> Vec2 {
+    let (chunk, index) = (chunk as i32, index as i32);
+    Vec2::new(
+        bounds.origin.x + (chunk % chunks_wide) * CHUNK_SIZE + index % CHUNK_SIZE,
+        bounds.origin.y + (chunk / chunks_wide) * CHUNK_SIZE + index / CHUNK_SIZE,
+    )
+}
+
+impl<T> Default for TileGrid<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Picks dense or sparse storage from the bounds and number of the collected tiles.
+impl<T> FromIterator<(Vec2, T)> for TileGrid<T> {
+    fn from_iter<I: IntoIterator<Item = (Vec2, T)>>(iter: I) -> Self {
+        let entries: Vec<(Vec2, T)> = iter.into_iter().collect();
+        let mut grid = match LevelBounds::from_tiles(entries.iter().map(|(pos, _)| pos)) {
+            Some(bounds) => Self::with_storage_for(bounds, entries.len()),
+            None => Self::new(),
+        };
+        for (pos, value) in entries {
+            grid.insert(pos, value);
+        }
+        grid
+    }
+}
+
+impl<T: Clone> Clone for TileGrid<T> {
+    fn clone(&self) -> Self {
+        let storage = match &self.storage {
+            Storage::Dense {
+                bounds,
+                chunks_wide,
+                chunks,
+            } => Storage::Dense {
+                bounds: *bounds,
+                chunks_wide: *chunks_wide,
+                chunks: chunks.clone(),
+            },
+            Storage::Sparse(map) => Storage::Sparse(map.clone()),
+        };
+        Self {
+            storage,
+            len: self.len,
+        }
+    }
+}
+
+impl<T: PartialEq> PartialEq for TileGrid<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len
+            && self
+                .iter()
+                .all(|(pos, value)| other.get(pos) == Some(value))
+    }
+}
+
+impl<T> IntoIterator for TileGrid<T> {
+    type Item = (Vec2, T);
+    type IntoIter = std::vec::IntoIter<(Vec2, T)>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.into_entries().into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounds(x: i32, y: i32, width: i32, height: i32) -> LevelBounds {
+        LevelBounds {
+            origin: Vec2::new(x, y),
+            size: Vec2::new(width, height),
+        }
+    }
+
+    /// A few hundred tiles scattered over all four quadrants.
+    fn entries() -> HashMap<Vec2, i32> {
+        (0..400)
+            .map(|i| (Vec2::new((i * 37) % 91 - 45, (i * 53) % 67 - 33), i))
+            .collect()
+    }
+
+    fn dense_chunks_wide<T>(grid: &TileGrid<T>) -> i32 {
+        match &grid.storage {
+            Storage::Dense { chunks_wide, .. } => *chunks_wide,
+            Storage::Sparse(_) => panic!("grid is sparse"),
+        }
+    }
+
+    #[test]
+    fn locate_and_position_round_trip() {
+        for bounds in [
+            bounds(0, 0, 40, 21),
+            bounds(-37, -5, 40, 21),
+            bounds(-64, -64, 16, 1),
+        ] {
+            let chunks_wide = (bounds.size.x + CHUNK_SIZE - 1) / CHUNK_SIZE;
+            for tile in bounds.tiles() {
+                let (chunk, index) = locate(&bounds, chunks_wide, tile).unwrap();
+                assert!(index < CHUNK_AREA);
+                assert!(position(&bounds, chunks_wide, chunk, index) == tile);
+            }
+            let max = bounds.max();
+            for outside in [
+                Vec2::new(bounds.origin.x - 1, bounds.origin.y),
+                Vec2::new(bounds.origin.x, bounds.origin.y - 1),
+                Vec2::new(max.x + 1, max.y),
+                Vec2::new(max.x, max.y + 1),
+            ] {
+                assert!(locate(&bounds, chunks_wide, outside).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn grows_to_fit_inserts_outside_the_bounds() {
+        let mut grid = TileGrid::with_bounds(bounds(0, 0, 16, 16));
+        // Filled row by row from above and to the left of the bounds.
+        let tiles: Vec<Vec2> = bounds(-12, -12, 40, 40).tiles().collect();
+        for (i, tile) in tiles.iter().enumerate() {
+            grid.insert(*tile, i);
+        }
+        assert!(grid.is_dense());
+        let expected: HashMap<Vec2, usize> =
+            tiles.iter().enumerate().map(|(i, t)| (*t, i)).collect();
+        assert_eq!(grid.len(), expected.len());
+        for (tile, value) in &expected {
+            assert_eq!(grid.get(*tile), Some(value));
+        }
+    }
+
+    #[test]
+    fn grows_geometrically() {
+        let mut grid = TileGrid::with_bounds(bounds(0, 0, 1, 1));
+        let mut regrows = 0;
+        let mut chunks_wide = dense_chunks_wide(&grid);
+        for x in 0..4096 {
+            grid.insert(Vec2::new(x, 0), ());
+            if dense_chunks_wide(&grid) != chunks_wide {
+                chunks_wide = dense_chunks_wide(&grid);
+                regrows += 1;
+            }
+        }
+        assert!(regrows <= 9, "regrew {} times", regrows);
+    }
+
+    #[test]
+    fn switches_to_sparse_when_mostly_empty() {
+        let mut grid: TileGrid<i32> = (0..16).map(|x| (Vec2::new(x, 0), x)).collect();
+        assert!(grid.is_dense());
+        grid.insert(Vec2::new(10_000, -10_000), -1);
+        assert!(!grid.is_dense());
+        assert_eq!(grid.len(), 17);
+        assert_eq!(grid.get(Vec2::new(10_000, -10_000)), Some(&-1));
+        for x in 0..16 {
+            assert_eq!(grid.get(Vec2::new(x, 0)), Some(&x));
+        }
+    }
+
+    #[test]
+    fn collects_into_dense_or_sparse_storage() {
+        let compact: TileGrid<()> = bounds(-8, -8, 64, 64).tiles().map(|t| (t, ())).collect();
+        assert!(compact.is_dense());
+        let scattered: TileGrid<()> = (0..10)
+            .map(|i| (Vec2::new(i * 1000, -i * 1000), ()))
+            .collect();
+        assert!(!scattered.is_dense());
+        assert_eq!(scattered.len(), 10);
+    }
+
+    #[test]
+    fn matches_a_hash_map() {
+        let map = entries();
+        let dense: TileGrid<i32> = map.iter().map(|(tile, value)| (*tile, *value)).collect();
+        let mut sparse = TileGrid::new();
+        for (tile, value) in &map {
+            sparse.insert(*tile, *value);
+        }
+        assert!(dense.is_dense());
+        assert!(!sparse.is_dense());
+        for grid in [&dense, &sparse] {
+            assert_eq!(grid.len(), map.len());
+            let iterated: HashMap<Vec2, i32> = grid.iter().map(|(t, v)| (t, *v)).collect();
+            assert!(iterated == map);
+            for tile in bounds(-50, -40, 100, 80).tiles() {
+                assert_eq!(grid.get(tile), map.get(&tile));
+            }
+        }
+        assert!(dense == sparse && sparse == dense);
+        assert!(dense.clone().into_iter().collect::<HashMap<_, _>>() == map);
+        assert!(sparse.clone().into_iter().collect::<HashMap<_, _>>() == map);
+    }
+
+    #[test]
+    fn compares_by_contents() {
+        let map = entries();
+        let grid: TileGrid<i32> = map.iter().map(|(tile, value)| (*tile, *value)).collect();
+        let mut changed = grid.clone();
+        let (tile, value) = map.iter().next().unwrap();
+        changed.insert(*tile, value + 1);
+        assert!(grid != changed);
+        let mut removed = grid.clone();
+        removed.remove(*tile);
+        assert!(grid != removed && removed != grid);
+        removed.insert(*tile, *value);
+        assert!(grid == removed);
+    }
+}
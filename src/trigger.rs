@@ -0,0 +1,188 @@
+//! Non-solid regions of a level that notice the player and NPCs crossing them, for cutscenes,
+//! traps and the like. A trigger is a `Sensor` body, its `Collider` covering the bounding box of
+//! its tiles, so the contacts found by `detect_collisions` are narrowed down to the bodies that
+//! are actually on one of its tiles, without sending collision events.
+
+use std::collections::{HashMap, HashSet};
+
+use bevy_ecs::prelude::*;
+use engine::types::{Vec2, Vec2F};
+
+use crate::collision::{sorted, Contacts, SKIN};
+use crate::components::*;
+use crate::resources::LevelBounds;
+
+#[derive(Component, Default)]
+pub struct Trigger {
+    /// Triggers spawned with the same name are merged into one.
+    pub name: Option<String>,
+    pub tiles: HashSet<Vec2>,
+    /// The bodies on the trigger as of the last `update_triggers`.
+    pub occupants: HashSet<Entity>,
+}
+
+impl Trigger {
+    pub fn new(name: Option<String>) -> Self {
+        Self {
+            name,
+            ..Default::default()
+        }
+    }
+    /// The position and `Collider` of the bounding box of the tiles.
+    pub fn bounding_box(&self) -> (Position, Collider) {
+        match LevelBounds::from_tiles(self.tiles.iter()) {
+            Some(bounds) => (
+                Position::new(bounds.origin.x as f32, bounds.origin.y as f32),
+                Collider::new_box(bounds.size.x as f32, bounds.size.y as f32),
+            ),
+            None => (Position::default(), Collider::new_box(0.0, 0.0)),
+        }
+    }
+    /// Whether the bounding box of a body covers any of the tiles.
+    fn covers(&self, collider: &Collider, pos: Vec2F) -> bool {
+        let (min, max) = collider.bounds(pos);
+        let (first_x, last_x) = ((min.x + SKIN).floor() as i32, (max.x - SKIN).ceil() as i32);
+        let (first_y, last_y) = ((min.y + SKIN).floor() as i32, (max.y - SKIN).ceil() as i32);
+        (first_y..last_y).any(|y| (first_x..last_x).any(|x| self.tiles.contains(&Vec2::new(x, y))))
+    }
+}
+
+/// Sent when the player or an NPC steps onto a trigger.
+pub struct TriggerEntered {
+    pub trigger: Entity,
+    pub body: Entity,
+}
+
+/// Sent when the player or an NPC steps off a trigger, or is despawned while on it.
+pub struct TriggerExited {
+    pub trigger: Entity,
+    pub body: Entity,
+}
+
+/// Updates who is on each `Trigger`, sending a `TriggerEntered` or `TriggerExited` for each body
+/// that stepped on or off it since the last frame.
+pub fn update_triggers(
+    mut trigger_query: Query<(Entity, &mut Trigger)>,
+    body_query: Query<(&Position, &Collider), Or<(With<Player>, With<Enemy>)>>,
+    contacts: Res<Contacts>,
+    mut entered: EventWriter<TriggerEntered>,
+    mut exited: EventWriter<TriggerExited>,
+) {
+    let mut touching: HashMap<Entity, Vec<Entity>> = HashMap::new();
+    for (a, b) in contacts.iter() {
+        if trigger_query.contains(a) {
+            touching.entry(a).or_default().push(b);
+        } else if trigger_query.contains(b) {
+            touching.entry(b).or_default().push(a);
+        }
+    }
+    for (entity, mut trigger) in trigger_query.iter_mut() {
+        let occupants: HashSet<Entity> = touching
+            .get(&entity)
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(|body| {
+                body_query
+                    .get(*body)
+                    .map_or(false, |(pos, collider)| trigger.covers(collider, pos.0))
+            })
+            .collect();
+        for body in sorted(occupants.difference(&trigger.occupants)) {
+            entered.send(TriggerEntered {
+                trigger: entity,
+                body,
+            });
+        }
+        for body in sorted(trigger.occupants.difference(&occupants)) {
+            exited.send(TriggerExited {
+                trigger: entity,
+                body,
+            });
+        }
+        trigger.occupants = occupants;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_ecs::event::Events;
+
+    use super::*;
+    use crate::collision::{detect_collisions, BroadphaseGrid, CollisionEnded, CollisionStarted};
+    use crate::entity_def::EntityDef;
+    use crate::resources::Level;
+    use crate::spawn::{spawn_level_entities, EntityRegistry};
+
+    fn world_with_triggers() -> World {
+        let mut world = World::new();
+        let background = (0..8).map(|x| (Vec2::new(x, 0), Vec2::new(0, 0))).collect();
+        let entities = [
+            (0, "trigger name=door"),
+            (3, "trigger name=door"),
+            (6, "trigger"),
+        ]
+        .iter()
+        .map(|(x, label)| (Vec2::new(*x, 0), EntityDef::parse(label).unwrap()))
+        .collect();
+        world.insert_resource(
+            Level::new("test")
+                .background_tiles(background)
+                .entities(entities)
+                .build(),
+        );
+        world.insert_resource(EntityRegistry::default());
+        world.insert_resource(BroadphaseGrid::default());
+        world.insert_resource(Contacts::default());
+        world.insert_resource(Events::<CollisionStarted>::default());
+        world.insert_resource(Events::<CollisionEnded>::default());
+        world.insert_resource(Events::<TriggerEntered>::default());
+        world.insert_resource(Events::<TriggerExited>::default());
+        assert!(spawn_level_entities(&mut world).is_empty());
+        world
+    }
+
+    fn run_detection(world: &mut World) {
+        let mut stage = SystemStage::single_threaded()
+            .with_system(detect_collisions)
+            .with_system(update_triggers.after(detect_collisions));
+        stage.run(world);
+    }
+
+    fn count<T: Send + Sync + 'static>(world: &World) -> usize {
+        let events = world.resource::<Events<T>>();
+        events.get_reader().iter(events).count()
+    }
+
+    #[test]
+    fn triggers_sharing_a_name_are_merged() {
+        let mut world = world_with_triggers();
+        let mut tiles: Vec<usize> = world
+            .query::<&Trigger>()
+            .iter(&world)
+            .map(|trigger| trigger.tiles.len())
+            .collect();
+        tiles.sort_unstable();
+        assert_eq!(tiles, vec![1, 2]);
+    }
+
+    #[test]
+    fn bodies_on_a_trigger_send_no_collision_events() {
+        let mut world = world_with_triggers();
+        world
+            .spawn()
+            .insert(Player)
+            .insert(Position::new(0.0, 0.0))
+            .insert(Collider::default());
+        // Inside the bounding box of the named trigger, but not on one of its tiles.
+        world
+            .spawn()
+            .insert(Enemy)
+            .insert(Position::new(1.0, 0.0))
+            .insert(Collider::default());
+        run_detection(&mut world);
+        assert_eq!(world.resource::<Contacts>().iter().count(), 2);
+        assert_eq!(count::<TriggerEntered>(&world), 1);
+        assert_eq!(count::<CollisionStarted>(&world), 0);
+    }
+}
@@ -1,5 +1,11 @@
 use crate::{SCREEN_HEIGHT, SCREEN_WIDTH};
-use engine::types::{VirtualKeyCode, WinitInputHelper};
+use engine::types::{Vec2, Vec2F, VirtualKeyCode, WinitInputHelper};
+
+/// The tile a point is on. Rounds down rather than toward zero, so that points left of or above
+/// the origin are on negative tiles.
+pub fn tile_of(point: Vec2F) -> Vec2 {
+    Vec2::new(point.x.floor() as i32, point.y.floor() as i32)
+}
 
 pub fn any_key_held(input: &WinitInputHelper, keys: &[VirtualKeyCode]) -> bool {
     for key in keys {
@@ -18,3 +24,29 @@ pub fn resolution_to_screen_space(resolution: (u32, u32), point: (f32, f32)) ->
     let y = (point.1 - y_sidebar / 2.0) * (SCREEN_HEIGHT as f32 / (resolution.1 - y_sidebar));
     (x as u32, y as u32)
 }
+
+pub fn wrap_text(text: &str, max_chars: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > max_chars {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// The point `alpha` of the way from `from` to `to`.
+pub fn lerp(from: Vec2F, to: Vec2F, alpha: f32) -> Vec2F {
+    Vec2F::new(
+        from.x + (to.x - from.x) * alpha,
+        from.y + (to.y - from.y) * alpha,
+    )
+}